@@ -0,0 +1,373 @@
+use crate::check;
+use crate::config::{ConfigBuilder, ConfigError, RuleAction};
+use crate::filter;
+use crate::handler_config::{HandlerConfig, SharedConfig};
+use crate::http_server::{self, Request, Response};
+use crate::metrics::{CacheStats, UpstreamStats};
+use futures::future::join_all;
+use hickory_proto::op::{LowerQuery, Query};
+use hickory_proto::rr::{Name, RecordType};
+use log::info;
+use serde_derive::Serialize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+
+#[derive(Debug, Serialize)]
+struct UpstreamStatus {
+    name: String,
+    enabled: bool,
+    #[serde(flatten)]
+    stats: UpstreamStats,
+    cache_hits: u64,
+    cache_misses: u64,
+}
+
+/// The answer of an upstream to a test query, and what the response rules made of it
+#[derive(Debug, Serialize)]
+struct UpstreamAnswer {
+    upstream: String,
+    /// accept or drop, or why there is no answer
+    verdict: &'static str,
+    /// The index of the response rule which matched
+    rule: Option<usize>,
+    answers: Vec<String>,
+    error: Option<String>,
+    latency_ms: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+struct TestResolve {
+    name: String,
+    r#type: String,
+    /// The index of the request rule which matched, none for the default upstreams
+    rule: Option<usize>,
+    upstreams: Vec<UpstreamAnswer>,
+}
+
+/// Serve the admin API to the clients presenting `token`. Reloads read the
+/// configuration again from `path`, they fail without one.
+pub async fn serve(
+    listener: TcpListener,
+    token: String,
+    config: SharedConfig,
+    path: Option<PathBuf>,
+) {
+    let token = Arc::new(token);
+    let path = Arc::new(path);
+    http_server::serve(listener, move |request: Request| {
+        let token = token.clone();
+        let config = config.clone();
+        let path = path.clone();
+        async move {
+            if !authorized(&request, &token) {
+                return Response::text("401 Unauthorized", "invalid token\n".to_string());
+            }
+            route(request, &config, path.as_ref().as_deref()).await
+        }
+    })
+    .await
+}
+
+fn authorized(request: &Request, token: &str) -> bool {
+    let Some(presented) = request
+        .headers
+        .get("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    // compare every byte so the time taken doesn't tell how much of it matched
+    presented.len() == token.len()
+        && presented
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn route(request: Request, shared: &SharedConfig, path: Option<&Path>) -> Response {
+    let config = shared.read().unwrap().clone();
+    let segments: Vec<_> = request.path.split('/').filter(|s| !s.is_empty()).collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["upstreams"]) => Response::json("200 OK", &upstreams(&config)),
+        ("POST", ["upstreams", name, action @ ("enable" | "disable")]) => {
+            if !config.resolvers.contains_key(*name) {
+                return Response::not_found();
+            }
+            let mut disabled = config.disabled.write().unwrap();
+            if *action == "enable" {
+                disabled.remove(*name);
+            } else {
+                disabled.insert(name.to_string());
+            }
+            info!("Upstream {} is {}d", name, action);
+            Response::text("200 OK", format!("{} {}d\n", name, action))
+        }
+        ("GET", ["config"]) => Response::text("200 OK", format!("{:#?}\n", config)),
+        ("POST", ["cache", "flush"]) => {
+            let name = match request.query.get("name").map(|name| fqdn(name)) {
+                Some(Ok(name)) => Some(name),
+                Some(Err(e)) => return Response::text("400 Bad Request", e),
+                None => None,
+            };
+            for resolver in config.resolvers.values() {
+                resolver.flush_cache(name.as_deref());
+            }
+            let flushed = match &name {
+                Some(name) => format!("flushed {}\n", name),
+                None => "flushed\n".to_string(),
+            };
+            info!("Cache {}", flushed.trim_end());
+            Response::text("200 OK", flushed)
+        }
+        ("POST", ["reload"]) => reload(shared, path, false).await,
+        ("POST", ["reload", "lists"]) => reload(shared, path, true).await,
+        ("GET", ["resolve"]) => {
+            let name = request.query.get("name").map(|name| fqdn(name));
+            let record_type = request.query.get("type").map_or(Ok(RecordType::A), |t| {
+                RecordType::from_str(&t.to_ascii_uppercase()).map_err(|e| e.to_string())
+            });
+            match (name, record_type) {
+                (Some(Ok(name)), Ok(record_type)) => {
+                    Response::json("200 OK", &test_resolve(&config, &name, record_type).await)
+                }
+                (None, _) => Response::text("400 Bad Request", "name is missing\n".to_string()),
+                (Some(Err(e)), _) | (_, Err(e)) => Response::text("400 Bad Request", e),
+            }
+        }
+        _ => Response::not_found(),
+    }
+}
+
+fn upstreams(config: &HandlerConfig) -> Vec<UpstreamStatus> {
+    let disabled = config.disabled.read().unwrap();
+    let mut upstreams: Vec<_> = config
+        .resolvers
+        .iter()
+        .map(|(name, resolver)| UpstreamStatus {
+            name: name.clone(),
+            enabled: !disabled.contains(name),
+            stats: config.metrics.upstream_stats(name),
+            cache_hits: resolver.cache_stats().map_or(0, CacheStats::hits),
+            cache_misses: resolver.cache_stats().map_or(0, CacheStats::misses),
+        })
+        .collect();
+    upstreams.sort_by(|a, b| a.name.cmp(&b.name));
+    upstreams
+}
+
+/// `name` in the form of the names of queries
+fn fqdn(name: &str) -> Result<String, String> {
+    let mut fqdn = Name::from_str(name)
+        .map_err(|e| format!("invalid name {:?}: {}\n", name, e))?
+        .to_lowercase();
+    fqdn.set_fqdn(true);
+    Ok(fqdn.to_string())
+}
+
+async fn reload(shared: &SharedConfig, path: Option<&Path>, lists_only: bool) -> Response {
+    let Some(path) = path else {
+        return Response::text(
+            "400 Bad Request",
+            "the config wasn't read from a file\n".to_string(),
+        );
+    };
+    // the queries are answered with the current config while the new one is built
+    let current = shared.read().unwrap().clone();
+    let built = {
+        let path = path.to_path_buf();
+        let current = current.clone();
+        // reading the file and building its resolvers block
+        tokio::task::spawn_blocking(move || reloaded(&path, &current, lists_only)).await
+    };
+    let reloaded = match built {
+        Ok(Ok(reloaded)) => reloaded,
+        Ok(Err(errors)) => return Response::text("400 Bad Request", errors),
+        Err(e) => return Response::text("500 Internal Server Error", format!("{}\n", e)),
+    };
+    let mut shared = shared.write().unwrap();
+    if !Arc::ptr_eq(&shared, &current) {
+        return Response::text(
+            "409 Conflict",
+            "the config was reloaded meanwhile\n".to_string(),
+        );
+    }
+    *shared = Arc::new(reloaded);
+    info!("Reloaded {}", path.display());
+    Response::text("200 OK", "reloaded\n".to_string())
+}
+
+/// Read the config at `path` and build it in place of `current`, or the errors in it
+fn reloaded(
+    path: &Path,
+    current: &HandlerConfig,
+    lists_only: bool,
+) -> Result<HandlerConfig, String> {
+    let config = ConfigBuilder::from_file(path)
+        .and_then(ConfigBuilder::build)
+        .map_err(|e| format!("{}\n", e))?;
+    // the upstreams added in code are not in the file
+    let errors: String = check::check(&config)
+        .into_iter()
+        .filter(|e| {
+            !matches!(e, ConfigError::UnknownUpstream(_, name) if current.custom.contains_key(name))
+        })
+        .map(|e| format!("{}\n", e))
+        .collect();
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(if lists_only {
+        current.reload_lists(config)
+    } else {
+        current.reload(config)
+    })
+}
+
+/// Ask every upstream of `name` at once, without the race of the handler
+async fn test_resolve(config: &HandlerConfig, name: &str, record_type: RecordType) -> TestResolve {
+    let query = LowerQuery::query(Query::query(
+        Name::from_str(name).unwrap_or_default(),
+        record_type,
+    ));
+    let (rule, upstreams) = filter::resolvers(config, &query);
+    let answers = join_all(upstreams.into_iter().map(|upstream| async move {
+        let mut answer = UpstreamAnswer {
+            verdict: "unknown",
+            rule: None,
+            answers: Vec::new(),
+            error: None,
+            latency_ms: None,
+            upstream,
+        };
+        let Some(resolver) = config.resolver(&answer.upstream) else {
+            if config.resolvers.contains_key(&answer.upstream) {
+                answer.verdict = "disabled";
+            }
+            return answer;
+        };
+        let started = Instant::now();
+        let lookup = tokio::time::timeout(
+            Duration::from_secs(5),
+            resolver.resolve(name, record_type, None),
+        )
+        .await;
+        answer.latency_ms = Some(started.elapsed().as_secs_f64() * 1000.0);
+        match lookup {
+            Ok(Ok(lookup)) => {
+                let (action, rule) =
+                    filter::check_response(config, name, &answer.upstream, lookup.records());
+                answer.verdict = match action {
+                    RuleAction::Accept => "accept",
+                    RuleAction::Drop => "drop",
+                };
+                answer.rule = rule;
+                answer.answers = lookup.records().iter().map(ToString::to_string).collect();
+            }
+            Ok(Err(e)) => {
+                answer.verdict = "error";
+                answer.error = Some(e.to_string());
+            }
+            Err(_) => answer.verdict = "timeout",
+        }
+        answer
+    }))
+    .await;
+    TestResolve {
+        name: name.to_string(),
+        r#type: record_type.to_string(),
+        rule,
+        upstreams: answers,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::RwLock;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    const CONFIG: &str = r#"
+        bind = "127.0.0.1:5300"
+        [upstreams.google]
+        address = ["192.0.2.53"]
+        network = "udp"
+    "#;
+
+    async fn request(addr: std::net::SocketAddr, head: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("{}\r\n\r\n", head).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn admin_api() {
+        let dir = std::env::temp_dir().join(format!("yadns-admin-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        std::fs::write(&path, CONFIG).unwrap();
+        let config = HandlerConfig::from(ConfigBuilder::from_file(&path).unwrap().build().unwrap());
+        let shared = Arc::new(RwLock::new(Arc::new(config)));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(
+            listener,
+            "secret".to_string(),
+            shared.clone(),
+            Some(path.clone()),
+        ));
+        let auth = "Authorization: Bearer secret";
+
+        let response = request(
+            addr,
+            "GET /upstreams HTTP/1.1\r\nAuthorization: Bearer nope",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 401"));
+
+        let response = request(
+            addr,
+            &format!("POST /upstreams/google/disable HTTP/1.1\r\n{auth}"),
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        let response = request(addr, &format!("GET /upstreams HTTP/1.1\r\n{auth}")).await;
+        assert!(response.contains("\"enabled\": false"));
+        // a disabled upstream isn't asked
+        let response = request(
+            addr,
+            &format!("GET /resolve?name=example.com HTTP/1.1\r\n{auth}"),
+        )
+        .await;
+        assert!(response.contains("\"verdict\": \"disabled\""));
+
+        let response = request(
+            addr,
+            &format!("POST /cache/flush?name=example.com HTTP/1.1\r\n{auth}"),
+        )
+        .await;
+        assert!(response.ends_with("flushed example.com.\n"));
+
+        // the upstreams stay off across reloads
+        std::fs::write(
+            &path,
+            format!("{CONFIG}\n[upstreams.local]\naddress = [\"192.0.2.54\"]\nnetwork = \"udp\"\n"),
+        )
+        .unwrap();
+        let response = request(addr, &format!("POST /reload HTTP/1.1\r\n{auth}")).await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        let config = shared.read().unwrap().clone();
+        assert!(config.resolvers.contains_key("local"));
+        assert!(config.resolver("google").is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,440 @@
+use crate::config::{Config, ResolverOpts, Upstream};
+use crate::handler::Handler;
+use crate::handler_config::HandlerConfig;
+use crate::metrics::Outcome;
+use crate::resolver::{RecursiveResolver, UpstreamResolver};
+use hickory_proto::op::{Message, MessageType, OpCode, Query, ResponseCode};
+use hickory_proto::rr::{Name, RecordType};
+use hickory_server::ServerFuture;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::Mutex;
+
+/// How the queries of a benchmark are sent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Udp,
+    Tcp,
+    #[cfg(feature = "dns-over-tls")]
+    Tls,
+    #[cfg(feature = "dns-over-https")]
+    Https,
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// The server to load, the handler of the config is run in process without it
+    pub server: Option<SocketAddr>,
+    pub protocol: Protocol,
+    /// The name in the certificate of the server, for tls and https
+    pub tls_host: Option<String>,
+    /// The number of queries in flight
+    pub concurrency: usize,
+    /// The queries sent per second, as fast as the server answers when not set
+    pub qps: Option<u32>,
+    /// The number of queries sent, by default each query once
+    pub count: Option<usize>,
+    pub timeout: Duration,
+}
+
+/// What a benchmark measured
+#[derive(Debug, Default)]
+pub struct Report {
+    pub sent: usize,
+    pub elapsed: Duration,
+    /// The latency of each response
+    pub latencies: Vec<Duration>,
+    pub errors: usize,
+    pub timeouts: usize,
+    pub rcodes: BTreeMap<String, usize>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = self.elapsed.as_secs_f64();
+        let share = |count: usize| count as f64 * 100.0 / self.sent.max(1) as f64;
+        writeln!(
+            f,
+            "Queries:   {} in {:.2}s, {:.1} qps",
+            self.sent,
+            seconds,
+            self.sent as f64 / seconds.max(f64::EPSILON)
+        )?;
+        writeln!(
+            f,
+            "Responses: {} ({:.1}%)",
+            self.latencies.len(),
+            share(self.latencies.len())
+        )?;
+        writeln!(f, "Errors:    {} ({:.1}%)", self.errors, share(self.errors))?;
+        writeln!(
+            f,
+            "Timeouts:  {} ({:.1}%)",
+            self.timeouts,
+            share(self.timeouts)
+        )?;
+        if !self.latencies.is_empty() {
+            let ms = |latency: Duration| latency.as_secs_f64() * 1000.0;
+            writeln!(
+                f,
+                "Latency:   min {:.2} ms, p50 {:.2} ms, p90 {:.2} ms, p99 {:.2} ms, max {:.2} ms",
+                ms(self.percentile(0.0)),
+                ms(self.percentile(0.5)),
+                ms(self.percentile(0.9)),
+                ms(self.percentile(0.99)),
+                ms(self.percentile(1.0))
+            )?;
+        }
+        let rcodes: Vec<_> = self
+            .rcodes
+            .iter()
+            .map(|(rcode, count)| format!("{} {}", rcode, count))
+            .collect();
+        writeln!(f, "Rcodes:    {}", rcodes.join(", "))
+    }
+}
+
+impl Report {
+    /// The latency which `quantile` of the responses didn't exceed
+    pub fn percentile(&self, quantile: f64) -> Duration {
+        let mut latencies = self.latencies.clone();
+        latencies.sort();
+        let rank = (quantile * latencies.len() as f64).ceil() as usize;
+        latencies[rank.clamp(1, latencies.len()) - 1]
+    }
+}
+
+/// Send `queries` as `options` say, to the handler of `config` unless a server is given
+pub async fn run(
+    queries: Vec<(Name, RecordType)>,
+    options: BenchOptions,
+    config: Option<Config>,
+) -> Result<Report, String> {
+    if queries.is_empty() {
+        return Err("no queries".to_string());
+    }
+    let count = options.count.unwrap_or(queries.len());
+
+    let mut server = None;
+    let address = match (options.server, config) {
+        (Some(address), _) => address,
+        (None, Some(config)) => {
+            if !matches!(options.protocol, Protocol::Udp | Protocol::Tcp) {
+                return Err("the handler only listens on udp and tcp".to_string());
+            }
+            let (address, handler) = serve(config.into())
+                .await
+                .map_err(|e| format!("failed to start the handler: {}", e))?;
+            server = Some(handler);
+            address
+        }
+        (None, None) => return Err("either a server or a config is needed".to_string()),
+    };
+    let client = client(address, &options)?;
+    let report = bench(
+        client,
+        queries,
+        options.concurrency.max(1),
+        options.qps,
+        count,
+    )
+    .await;
+    if let Some(mut server) = server {
+        let _ = server.shutdown_gracefully().await;
+    }
+    Ok(report)
+}
+
+/// The queries in `contents`: the names of a JSON or CSV query log, or names each
+/// followed by an optional record type
+pub fn read_queries(
+    contents: &str,
+    record_type: RecordType,
+) -> Result<Vec<(Name, RecordType)>, String> {
+    let mut queries = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("timestamp,") {
+            continue;
+        }
+        let error = |e: &dyn fmt::Display| format!("line {}: {}", number + 1, e);
+        let (name, query_type) = if line.starts_with('{') {
+            let entry: serde_json::Value = serde_json::from_str(line).map_err(|e| error(&e))?;
+            let field = |key| entry[key].as_str().map(str::to_string);
+            (
+                field("qname").ok_or_else(|| error(&"qname is missing"))?,
+                field("qtype"),
+            )
+        } else if line.contains(',') {
+            // the qname and qtype columns of a CSV query log
+            let fields: Vec<_> = line.split(',').collect();
+            match (fields.get(3), fields.get(4)) {
+                (Some(name), Some(query_type)) => (name.to_string(), Some(query_type.to_string())),
+                _ => return Err(error(&"not a query log line")),
+            }
+        } else {
+            let mut fields = line.split_whitespace();
+            (
+                fields.next().unwrap_or_default().to_string(),
+                fields.next().map(str::to_string),
+            )
+        };
+        let name = Name::from_str(&name).map_err(|e| error(&e))?;
+        let query_type = match query_type {
+            Some(query_type) => RecordType::from_str(&query_type.to_ascii_uppercase())
+                .map_err(|_| error(&format!("invalid record type {:?}", query_type)))?,
+            None => record_type,
+        };
+        queries.push((name, query_type));
+    }
+    Ok(queries)
+}
+
+/// Serve `config` on an unused port of the loopback address over UDP and TCP
+async fn serve(config: HandlerConfig) -> std::io::Result<(SocketAddr, ServerFuture<Handler>)> {
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let address = socket.local_addr()?;
+    let listener = TcpListener::bind(address).await?;
+    let mut server = ServerFuture::new(Handler::new(config));
+    server.register_socket(socket);
+    server.register_listener(listener, Duration::from_secs(10));
+    Ok((address, server))
+}
+
+/// A client of the server at `address`, built like an upstream
+fn client(address: SocketAddr, options: &BenchOptions) -> Result<RecursiveResolver, String> {
+    let upstream = match options.protocol {
+        Protocol::Udp => Upstream::UdpUpstream {
+            address: vec![address],
+            proxy: None,
+            bind: Default::default(),
+            ecs: Default::default(),
+            dnssec: None,
+        },
+        Protocol::Tcp => Upstream::TcpUpstream {
+            address: vec![address],
+            proxy: None,
+            bind: Default::default(),
+            ecs: Default::default(),
+            dnssec: None,
+        },
+        #[cfg(feature = "dns-over-tls")]
+        Protocol::Tls => Upstream::TlsUpstream {
+            address: vec![address],
+            tls_host: options.tls_host.clone().ok_or("tls-host is missing")?,
+            cert_hashes: Vec::new(),
+            proxy: None,
+            bind: Default::default(),
+            ecs: Default::default(),
+            dnssec: None,
+        },
+        #[cfg(feature = "dns-over-https")]
+        Protocol::Https => Upstream::HttpsUpstream {
+            address: vec![address],
+            tls_host: options.tls_host.clone().ok_or("tls-host is missing")?,
+            path: None,
+            headers: Default::default(),
+            cert_hashes: Vec::new(),
+            proxy: None,
+            bind: Default::default(),
+            ecs: Default::default(),
+            dnssec: None,
+        },
+    };
+    let options = ResolverOpts {
+        timeout: options.timeout,
+        ip_strategy: None,
+        cache_size: 0,
+        dnssec: false,
+    };
+    Ok(RecursiveResolver::from((&upstream, Some(options))))
+}
+
+/// Send `count` of `queries` in turn through `client`, `concurrency` at once and at
+/// most `qps` a second
+pub async fn bench(
+    client: RecursiveResolver,
+    queries: Vec<(Name, RecordType)>,
+    concurrency: usize,
+    qps: Option<u32>,
+    count: usize,
+) -> Report {
+    let client = Arc::new(client);
+    let queries = Arc::new(queries);
+    let next = Arc::new(AtomicUsize::new(0));
+    let pace = qps.filter(|qps| *qps > 0).map(|qps| {
+        Arc::new(Mutex::new(tokio::time::interval(
+            Duration::from_secs(1) / qps,
+        )))
+    });
+    let started = Instant::now();
+    let workers: Vec<_> = (0..concurrency)
+        .map(|_| {
+            let (client, queries, next, pace) =
+                (client.clone(), queries.clone(), next.clone(), pace.clone());
+            tokio::spawn(async move {
+                let mut results = Vec::new();
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    if index >= count {
+                        break;
+                    }
+                    if let Some(pace) = &pace {
+                        pace.lock().await.tick().await;
+                    }
+                    let (name, record_type) = &queries[index % queries.len()];
+                    let mut message = Message::new();
+                    message
+                        .set_id(rand::random())
+                        .set_message_type(MessageType::Query)
+                        .set_op_code(OpCode::Query)
+                        .set_recursion_desired(true)
+                        .add_query(Query::query(name.clone(), *record_type));
+                    let sent = Instant::now();
+                    let result = client.forward(&message, None).await;
+                    results.push(match result {
+                        Ok(response) => Ok((sent.elapsed(), response.response_code())),
+                        Err(e) => Err(Outcome::from(&e)),
+                    });
+                }
+                results
+            })
+        })
+        .collect();
+
+    let mut report = Report::default();
+    for worker in workers {
+        for result in worker.await.unwrap_or_default() {
+            report.sent += 1;
+            match result {
+                Ok((latency, rcode)) => {
+                    report.latencies.push(latency);
+                    *report.rcodes.entry(rcode_name(rcode)).or_default() += 1;
+                }
+                Err(Outcome::Timeout) => report.timeouts += 1,
+                Err(_) => report.errors += 1,
+            }
+        }
+    }
+    report.elapsed = started.elapsed();
+    report
+}
+
+fn rcode_name(rcode: ResponseCode) -> String {
+    format!("{:?}", rcode).to_uppercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConfigBuilder;
+    use hickory_proto::rr::rdata::A;
+    use hickory_proto::rr::{RData, Record};
+
+    /// An upstream answering every query with 192.0.2.1, or NXDOMAIN for the names
+    /// under `missing.`
+    async fn mock_upstream() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+                let request = Message::from_vec(&buf[..len]).unwrap();
+                let name = request.queries()[0].name().clone();
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .set_recursion_desired(true)
+                    .set_recursion_available(true)
+                    .add_queries(request.queries().to_vec());
+                if name.to_string().ends_with("missing.") {
+                    response.set_response_code(ResponseCode::NXDomain);
+                } else {
+                    response.add_answer(Record::from_rdata(
+                        name,
+                        60,
+                        RData::A(A::new(192, 0, 2, 1)),
+                    ));
+                }
+                let response = response.to_vec().unwrap();
+                socket.send_to(&response, peer).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn read_query_logs() {
+        let queries = read_queries(
+            "# names\nexample.com\nexample.org aaaa\n\
+             {\"qname\":\"example.net.\",\"qtype\":\"MX\"}\n\
+             timestamp,client,listener,qname,qtype\n\
+             2024-01-01T00:00:00.000Z,127.0.0.1:53000,udp,example.edu.,TXT,,local,local,,NOERROR,,1.5\n",
+            RecordType::A,
+        )
+        .unwrap();
+        let queries: Vec<_> = queries
+            .iter()
+            .map(|(name, record_type)| format!("{} {}", name, record_type))
+            .collect();
+        assert_eq!(
+            queries,
+            [
+                "example.com A",
+                "example.org AAAA",
+                "example.net. MX",
+                "example.edu. TXT"
+            ]
+        );
+        assert!(read_queries("example.com AAA", RecordType::A).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn bench_handler() {
+        let upstream = mock_upstream().await;
+        let config = toml::from_str::<ConfigBuilder>(&format!(
+            r#"
+            bind = "127.0.0.1:5300"
+            [upstreams.mock]
+            address = ["{}"]
+            network = "udp"
+            "#,
+            upstream
+        ))
+        .unwrap()
+        .build()
+        .unwrap();
+        let (address, mut server) = serve(config.into()).await.unwrap();
+        let queries = read_queries("a.example\nb.missing\n", RecordType::A).unwrap();
+        let options = BenchOptions {
+            server: None,
+            protocol: Protocol::Udp,
+            tls_host: None,
+            concurrency: 4,
+            qps: None,
+            count: None,
+            timeout: Duration::from_secs(5),
+        };
+        let report = bench(client(address, &options).unwrap(), queries, 4, None, 20).await;
+        assert_eq!(report.sent, 20);
+        assert_eq!(report.latencies.len(), 20);
+        assert_eq!(report.rcodes["NOERROR"], 10);
+        assert_eq!(report.rcodes["NXDOMAIN"], 10);
+        assert!(report.percentile(0.5) <= report.percentile(0.99));
+        assert!(
+            report
+                .to_string()
+                .contains("Rcodes:    NOERROR 10, NXDOMAIN 10")
+        );
+
+        let _ = server.shutdown_gracefully().await;
+    }
+}
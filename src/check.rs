@@ -0,0 +1,148 @@
+use crate::config::{Config, ConfigError};
+use regex::{Regex, RegexSet};
+
+/// The problems of `config` which the parser lets through: the rules referring to
+/// unknown upstreams, domains or ranges, and the lists which can't be read
+pub fn check(config: &Config) -> Vec<ConfigError> {
+    let mut errors = Vec::new();
+    let mut upstreams = |rule: &str, names: &[String]| {
+        for name in names {
+            if !config.upstreams.contains_key(name) {
+                errors.push(ConfigError::UnknownUpstream(rule.to_string(), name.clone()));
+            }
+        }
+    };
+    for (index, rule) in config.request_rules.iter().enumerate() {
+        upstreams(&format!("requests[{}]", index), &rule.upstreams);
+    }
+    for (index, rule) in config.response_rules.iter().enumerate() {
+        if let Some(names) = &rule.upstreams {
+            upstreams(&format!("responses[{}]", index), names);
+        }
+    }
+
+    let tags = config
+        .request_rules
+        .iter()
+        .enumerate()
+        .map(|(index, rule)| (format!("requests[{}]", index), &rule.domains, &None))
+        .chain(
+            config
+                .response_rules
+                .iter()
+                .enumerate()
+                .map(|(index, rule)| {
+                    (format!("responses[{}]", index), &rule.domains, &rule.ranges)
+                }),
+        );
+    for (rule, domains, ranges) in tags {
+        for pattern in domains.iter().flatten() {
+            let tag = pattern.trim_start_matches('!');
+            if !config.domains.contains_key(tag) {
+                errors.push(ConfigError::UnknownDomains(rule.clone(), tag.to_string()));
+            }
+        }
+        for pattern in ranges.iter().flatten() {
+            let tag = pattern.trim_start_matches('!');
+            if !config.ranges.contains_key(tag) {
+                errors.push(ConfigError::UnknownRanges(rule.clone(), tag.to_string()));
+            }
+        }
+    }
+
+    let mut names: Vec<_> = config.domains.keys().collect();
+    names.sort();
+    for name in names {
+        match config.domains[name].build() {
+            // only compile them one by one to find the faulty one
+            Ok(domains) if RegexSet::new(&domains.regex_set).is_err() => {
+                for regex in domains.regex_set {
+                    if let Err(e) = Regex::new(&regex) {
+                        errors.push(ConfigError::InvalidRegex(
+                            format!("domains.{}", name),
+                            regex,
+                            e,
+                        ));
+                    }
+                }
+            }
+            Ok(_) => (),
+            Err(e) => errors.push(e),
+        }
+    }
+    let mut names: Vec<_> = config.ranges.keys().collect();
+    names.sort();
+    for name in names {
+        if let Err(e) = config.ranges[name].build(name) {
+            errors.push(e);
+        }
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConfigBuilder;
+
+    #[test]
+    fn check_references() {
+        let config = serde_yaml::from_str::<ConfigBuilder>(
+            r#"
+            bind: 127.0.0.1:5300
+            upstreams:
+                local:
+                    address: [192.0.2.53]
+                    network: udp
+            domains:
+                ads:
+                    list: ["regexp:^ads(", example.com]
+                missing:
+                    files: [/nonexistent/domains.txt]
+            ranges:
+                cn:
+                    list: [198.51.100.0/33]
+            requests:
+                - domains: ["!ads", cn]
+                  upstreams: [local, remote]
+            responses:
+                - upstreams: [google]
+                  ranges: ["!lan"]
+                  action: drop
+            "#,
+        )
+        .unwrap()
+        .build()
+        .unwrap();
+        let errors: Vec<_> = check(&config).iter().map(ToString::to_string).collect();
+        assert_eq!(errors.len(), 7);
+        assert_eq!(errors[0], "requests[0]: unknown upstream \"remote\"");
+        assert_eq!(errors[1], "responses[0]: unknown upstream \"google\"");
+        assert_eq!(errors[2], "requests[0]: unknown domains \"cn\"");
+        assert_eq!(errors[3], "responses[0]: unknown ranges \"lan\"");
+        assert!(errors[4].starts_with("domains.ads: invalid regex \"^ads(\""));
+        assert!(errors[5].starts_with("/nonexistent/domains.txt: No such file or directory"));
+        assert!(errors[6].starts_with("ranges.cn: invalid range \"198.51.100.0/33\""));
+    }
+
+    #[test]
+    fn invalid_record_type() {
+        let builder = serde_yaml::from_str::<ConfigBuilder>(
+            r#"
+            bind: 127.0.0.1:5300
+            upstreams:
+                local:
+                    address: [192.0.2.53]
+                    network: udp
+            requests:
+                - types: [AAA]
+                  upstreams: [local]
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(
+            builder.build(),
+            Err(ConfigError::InvalidRecordType(t)) if t == "AAA"
+        ));
+    }
+}
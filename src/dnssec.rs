@@ -0,0 +1,63 @@
+use hickory_proto::ProtoErrorKind;
+use hickory_proto::rr::{Record, RecordType};
+use hickory_resolver::ResolveError;
+
+/// Whether some of `records` failed DNSSEC validation
+pub fn is_bogus(records: &[Record]) -> bool {
+    records.iter().any(|record| record.proof().is_bogus())
+}
+
+/// Whether `error` is a negative answer whose SOA or authority records failed DNSSEC
+/// validation
+pub fn is_bogus_error(error: &ResolveError) -> bool {
+    match error.proto().map(|e| e.kind()) {
+        Some(ProtoErrorKind::NoRecordsFound {
+            soa, authorities, ..
+        }) => {
+            soa.as_ref().is_some_and(|soa| soa.proof().is_bogus())
+                || authorities.as_deref().is_some_and(is_bogus)
+        }
+        _ => false,
+    }
+}
+
+/// Whether `records` are all proven secure. RRSIGs are skipped as only the ones
+/// used for a proof are marked.
+pub fn is_secure(records: &[Record]) -> bool {
+    let mut records = records
+        .iter()
+        .filter(|record| record.record_type() != RecordType::RRSIG)
+        .peekable();
+    records.peek().is_some() && records.all(|record| record.proof().is_secure())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::dnssec::Proof;
+    use hickory_proto::rr::rdata::A;
+    use hickory_proto::rr::{Name, RData};
+
+    fn record(record_type: RecordType, proof: Proof) -> Record {
+        let rdata = match record_type {
+            RecordType::A => RData::A(A::new(192, 0, 2, 1)),
+            _ => RData::Update0(record_type),
+        };
+        let mut record = Record::from_rdata(Name::root(), 60, rdata);
+        record.set_proof(proof);
+        record
+    }
+
+    #[test]
+    fn record_proofs() {
+        let secure = record(RecordType::A, Proof::Secure);
+        let rrsig = record(RecordType::RRSIG, Proof::Indeterminate);
+        assert!(is_secure(&[secure.clone(), rrsig.clone()]));
+        assert!(!is_secure(&[rrsig]));
+        assert!(!is_secure(&[]));
+        let insecure = record(RecordType::A, Proof::Insecure);
+        assert!(!is_secure(&[secure.clone(), insecure.clone()]));
+        assert!(!is_bogus(&[secure.clone(), insecure]));
+        assert!(is_bogus(&[secure, record(RecordType::A, Proof::Bogus)]));
+    }
+}
@@ -0,0 +1,478 @@
+use crate::config::DnstapConfig;
+use crate::metrics::Metrics;
+use futures::Future;
+use hickory_proto::op::Message;
+use hickory_proto::serialize::binary::BinEncodable;
+use hickory_proto::xfer::{DnsResponse, Protocol};
+use hickory_server::server::Request;
+use log::{debug, warn};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, Receiver, Sender, error::TrySendError};
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+// Frame Streams control frames
+const CONTROL_ACCEPT: u32 = 1;
+const CONTROL_START: u32 = 2;
+const CONTROL_READY: u32 = 4;
+const FIELD_CONTENT_TYPE: u32 = 1;
+
+/// The types of dnstap messages which are sent
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MessageKind {
+    ClientQuery = 5,
+    ClientResponse = 6,
+    ForwarderQuery = 7,
+    ForwarderResponse = 8,
+}
+
+/// A dnstap message about a query or its response
+#[derive(Debug)]
+struct Event<'a> {
+    kind: MessageKind,
+    client: Option<SocketAddr>,
+    /// The name server of forwarder messages
+    server: Option<SocketAddr>,
+    protocol: Option<Protocol>,
+    query_time: SystemTime,
+    query: Option<Vec<u8>>,
+    response_time: Option<SystemTime>,
+    response: Option<Vec<u8>>,
+    /// The upstream of forwarder messages
+    upstream: Option<&'a str>,
+}
+
+impl Event<'_> {
+    /// The `Dnstap` protobuf message holding this event
+    fn encode(&self, identity: &[u8]) -> Vec<u8> {
+        let mut message = Vec::new();
+        field_varint(&mut message, 1, self.kind as u64);
+        if let Some(address) = self.client.or(self.server) {
+            let family = if address.is_ipv4() { 1 } else { 2 };
+            field_varint(&mut message, 2, family);
+        }
+        if let Some(client) = self.client {
+            field_bytes(&mut message, 4, &octets(client.ip()));
+            field_varint(&mut message, 6, client.port().into());
+        }
+        if let Some(server) = self.server {
+            field_bytes(&mut message, 5, &octets(server.ip()));
+            field_varint(&mut message, 7, server.port().into());
+        }
+        let protocol = match self.protocol {
+            Some(Protocol::Udp) => Some(1),
+            Some(Protocol::Tcp) => Some(2),
+            #[cfg(feature = "dns-over-tls")]
+            Some(Protocol::Tls) => Some(3),
+            #[cfg(feature = "dns-over-https")]
+            Some(Protocol::Https) => Some(4),
+            #[cfg(feature = "dns-over-h3")]
+            Some(Protocol::H3) => Some(4),
+            #[cfg(feature = "dns-over-quic")]
+            Some(Protocol::Quic) => Some(7),
+            _ => None,
+        };
+        if let Some(protocol) = protocol {
+            field_varint(&mut message, 3, protocol);
+        }
+        let (secs, nanos) = timestamp(self.query_time);
+        field_varint(&mut message, 8, secs);
+        field_fixed32(&mut message, 9, nanos);
+        if let Some(query) = &self.query {
+            field_bytes(&mut message, 10, query);
+        }
+        if let Some(response_time) = self.response_time {
+            let (secs, nanos) = timestamp(response_time);
+            field_varint(&mut message, 12, secs);
+            field_fixed32(&mut message, 13, nanos);
+        }
+        if let Some(response) = &self.response {
+            field_bytes(&mut message, 14, response);
+        }
+
+        let mut dnstap = Vec::new();
+        if !identity.is_empty() {
+            field_bytes(&mut dnstap, 1, identity);
+        }
+        let version = concat!("yadns ", env!("CARGO_PKG_VERSION"));
+        field_bytes(&mut dnstap, 2, version.as_bytes());
+        if let Some(upstream) = self.upstream {
+            field_bytes(&mut dnstap, 3, upstream.as_bytes());
+        }
+        field_bytes(&mut dnstap, 14, &message);
+        // MESSAGE
+        field_varint(&mut dnstap, 15, 1);
+        dnstap
+    }
+}
+
+fn octets(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+fn timestamp(time: SystemTime) -> (u64, u32) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (since_epoch.as_secs(), since_epoch.subsec_nanos())
+}
+
+fn varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn field_varint(buf: &mut Vec<u8>, field: u64, value: u64) {
+    varint(buf, field << 3);
+    varint(buf, value);
+}
+
+fn field_bytes(buf: &mut Vec<u8>, field: u64, value: &[u8]) {
+    varint(buf, field << 3 | 2);
+    varint(buf, value.len() as u64);
+    buf.extend_from_slice(value);
+}
+
+fn field_fixed32(buf: &mut Vec<u8>, field: u64, value: u32) {
+    varint(buf, field << 3 | 5);
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Sends dnstap frames from a task of its own. Frames are dropped, and counted,
+/// when the buffer is full or they could not be written.
+#[derive(Debug)]
+pub struct Dnstap {
+    identity: Vec<u8>,
+    frames: Sender<Vec<u8>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Dnstap {
+    pub fn new(config: DnstapConfig, metrics: Arc<Metrics>) -> Self {
+        let (frames, receiver) = mpsc::channel(config.buffer.unwrap_or(10000).max(1));
+        let identity = config.identity.clone().unwrap_or_default().into_bytes();
+        tokio::spawn(run(config, receiver, metrics.clone()));
+        Dnstap {
+            identity,
+            frames,
+            metrics,
+        }
+    }
+
+    pub fn client_query(&self, request: &Request, query_time: SystemTime) {
+        self.send(Event {
+            kind: MessageKind::ClientQuery,
+            client: Some(request.src()),
+            server: None,
+            protocol: Some(request.protocol()),
+            query_time,
+            query: request.to_bytes().ok(),
+            response_time: None,
+            response: None,
+            upstream: None,
+        });
+    }
+
+    pub fn client_response(&self, request: &Request, query_time: SystemTime, response: &Message) {
+        self.send(Event {
+            kind: MessageKind::ClientResponse,
+            client: Some(request.src()),
+            server: None,
+            protocol: Some(request.protocol()),
+            query_time,
+            query: request.to_bytes().ok(),
+            response_time: Some(SystemTime::now()),
+            response: response.to_vec().ok(),
+            upstream: None,
+        });
+    }
+
+    /// Send the frames of the exchange of `query` with the name server `server`
+    /// of `upstream`, its response is none if it failed
+    fn forwarder(
+        &self,
+        upstream: &str,
+        server: SocketAddr,
+        protocol: Protocol,
+        query_time: SystemTime,
+        query: &Message,
+        response: Option<&DnsResponse>,
+    ) {
+        // the ID of UDP queries is only picked when they are sent
+        let query = match response {
+            Some(response) if response.id() != query.id() => {
+                let mut query = query.clone();
+                query.set_id(response.id());
+                query.to_vec().ok()
+            }
+            _ => query.to_vec().ok(),
+        };
+        let event = |kind| Event {
+            kind,
+            client: None,
+            server: Some(server),
+            protocol: Some(protocol),
+            query_time,
+            query: query.clone(),
+            response_time: None,
+            response: None,
+            upstream: Some(upstream),
+        };
+        self.send(event(MessageKind::ForwarderQuery));
+        if let Some(response) = response {
+            self.send(Event {
+                response_time: Some(SystemTime::now()),
+                response: Some(response.as_buffer().to_vec()),
+                ..event(MessageKind::ForwarderResponse)
+            });
+        }
+    }
+
+    fn send(&self, event: Event) {
+        match self.frames.try_send(event.encode(&self.identity)) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => self.metrics.dnstap_dropped("full"),
+            Err(TrySendError::Closed(_)) => self.metrics.dnstap_dropped("closed"),
+        }
+    }
+}
+
+tokio::task_local! {
+    static TAP: Tap;
+}
+
+/// Where the messages exchanged with the name servers of an upstream are sent
+#[derive(Clone)]
+pub struct Tap {
+    dnstap: Arc<Dnstap>,
+    upstream: Arc<str>,
+}
+
+/// Run `lookup` of `upstream`, tapping the messages its connections exchange
+/// with the name servers. Lookups answered from the cache send no frames.
+pub async fn tap<F: Future>(dnstap: Option<Arc<Dnstap>>, upstream: &str, lookup: F) -> F::Output {
+    match dnstap {
+        Some(dnstap) => {
+            let tap = Tap {
+                dnstap,
+                upstream: upstream.into(),
+            };
+            TAP.scope(tap, lookup).await
+        }
+        None => lookup.await,
+    }
+}
+
+impl Tap {
+    /// The tap of the lookup of the current task, if any
+    pub fn current() -> Option<Tap> {
+        TAP.try_with(Tap::clone).ok()
+    }
+
+    pub fn exchanged(
+        &self,
+        server: SocketAddr,
+        protocol: Protocol,
+        query_time: SystemTime,
+        query: &Message,
+        response: Option<&DnsResponse>,
+    ) {
+        self.dnstap.forwarder(
+            &self.upstream,
+            server,
+            protocol,
+            query_time,
+            query,
+            response,
+        );
+    }
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Write the frames to the receiver of `config`, connecting again when the
+/// connection fails
+async fn run(config: DnstapConfig, mut frames: Receiver<Vec<u8>>, metrics: Arc<Metrics>) {
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        match connect(&config).await {
+            Ok(stream) => {
+                debug!("Connected to the dnstap receiver");
+                backoff = Duration::from_secs(1);
+                if write_frames(stream, &mut frames, &metrics).await {
+                    return;
+                }
+            }
+            Err(e) => {
+                warn!("Failed to connect to the dnstap receiver: {}", e);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_secs(60));
+            }
+        }
+    }
+}
+
+async fn connect(config: &DnstapConfig) -> std::io::Result<Box<dyn Stream>> {
+    let mut stream: Box<dyn Stream> = match (&config.unix, config.tcp) {
+        #[cfg(unix)]
+        (Some(path), _) => Box::new(tokio::net::UnixStream::connect(path).await?),
+        (_, Some(addr)) => Box::new(TcpStream::connect(addr).await?),
+        _ => return Err(std::io::ErrorKind::Unsupported.into()),
+    };
+    // the bidirectional handshake
+    stream.write_all(&control(CONTROL_READY)).await?;
+    stream.flush().await?;
+    let accept = tokio::time::timeout(Duration::from_secs(5), read_control(&mut stream))
+        .await
+        .map_err(|_| std::io::ErrorKind::TimedOut)??;
+    if accept != CONTROL_ACCEPT {
+        return Err(std::io::Error::other(format!(
+            "unexpected control frame {}",
+            accept
+        )));
+    }
+    stream.write_all(&control(CONTROL_START)).await?;
+    Ok(stream)
+}
+
+/// Write the frames until the connection fails, or there are no more frames to
+/// write and true is returned
+async fn write_frames(
+    stream: Box<dyn Stream>,
+    frames: &mut Receiver<Vec<u8>>,
+    metrics: &Metrics,
+) -> bool {
+    let mut stream = BufWriter::new(stream);
+    loop {
+        let Some(frame) = frames.recv().await else {
+            return true;
+        };
+        let mut result = write_frame(&mut stream, &frame).await;
+        // flush once the frames waiting are written
+        while result.is_ok()
+            && let Ok(frame) = frames.try_recv()
+        {
+            result = write_frame(&mut stream, &frame).await;
+        }
+        if result.is_ok() {
+            result = stream.flush().await;
+        }
+        if let Err(e) = result {
+            warn!("Failed to write to the dnstap receiver: {}", e);
+            metrics.dnstap_dropped("write");
+            return false;
+        }
+    }
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(stream: &mut W, frame: &[u8]) -> std::io::Result<()> {
+    stream
+        .write_all(&(frame.len() as u32).to_be_bytes())
+        .await?;
+    stream.write_all(frame).await
+}
+
+/// A control frame with the dnstap content type
+fn control(kind: u32) -> Vec<u8> {
+    let mut payload = kind.to_be_bytes().to_vec();
+    payload.extend_from_slice(&FIELD_CONTENT_TYPE.to_be_bytes());
+    payload.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+    payload.extend_from_slice(CONTENT_TYPE);
+    let mut frame = 0u32.to_be_bytes().to_vec();
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+/// The type of the control frame read from `stream`
+async fn read_control<R: AsyncRead + Unpin>(stream: &mut R) -> std::io::Result<u32> {
+    if stream.read_u32().await? != 0 {
+        return Err(std::io::Error::other("expected a control frame"));
+    }
+    let len = stream.read_u32().await? as usize;
+    if !(4..=512).contains(&len) {
+        return Err(std::io::Error::other("invalid control frame"));
+    }
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload).await?;
+    Ok(u32::from_be_bytes(payload[..4].try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::op::Query;
+    use hickory_proto::rr::{Name, RecordType};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn encode_varint() {
+        let mut buf = Vec::new();
+        varint(&mut buf, 1);
+        varint(&mut buf, 300);
+        assert_eq!(buf, [0x01, 0xac, 0x02]);
+    }
+
+    #[tokio::test]
+    async fn frame_streams() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let dnstap = Dnstap::new(
+            DnstapConfig {
+                unix: None,
+                tcp: Some(listener.local_addr().unwrap()),
+                identity: Some("router".to_string()),
+                buffer: None,
+            },
+            metrics,
+        );
+        let mut query = Message::new();
+        query.set_id(1234).add_query(Query::query(
+            Name::from_ascii("example.com.").unwrap(),
+            RecordType::A,
+        ));
+        let server = "192.0.2.53:853".parse().unwrap();
+        dnstap.forwarder(
+            "google",
+            server,
+            Protocol::Tcp,
+            SystemTime::now(),
+            &query,
+            None,
+        );
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        assert_eq!(read_control(&mut stream).await.unwrap(), CONTROL_READY);
+        stream.write_all(&control(CONTROL_ACCEPT)).await.unwrap();
+        assert_eq!(read_control(&mut stream).await.unwrap(), CONTROL_START);
+        let len = stream.read_u32().await.unwrap() as usize;
+        let mut frame = vec![0; len];
+        stream.read_exact(&mut frame).await.unwrap();
+
+        // identity, then the upstream as extra
+        assert_eq!(&frame[..8], b"\x0a\x06router");
+        let extra = b"\x1a\x06google";
+        assert!(frame.windows(extra.len()).any(|w| w == extra));
+        // the name server address and port, and the protocol
+        let family = [2 << 3, 1];
+        assert!(frame.windows(2).any(|w| w == family));
+        let address = [5 << 3 | 2, 4, 192, 0, 2, 53];
+        assert!(frame.windows(address.len()).any(|w| w == address));
+        let port = [7 << 3, 0xd5, 0x06];
+        assert!(frame.windows(port.len()).any(|w| w == port));
+        let protocol = [3 << 3, 2];
+        assert!(frame.windows(2).any(|w| w == protocol));
+        // MESSAGE is the last field
+        assert_eq!(&frame[len - 2..], [15 << 3, 1]);
+        let query = query.to_vec().unwrap();
+        assert!(frame.windows(query.len()).any(|w| w == query));
+    }
+}
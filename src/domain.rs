@@ -40,9 +40,9 @@ mod tests {
         let ds: DomainSuffix = "// BEGIN ICANN DOMAINS\ndomain.geek\nbbs\n"
             .parse()
             .unwrap();
-        assert_eq!(ds.contains("domain.geek"), true);
-        assert_eq!(ds.contains("www.domain.geek"), true);
-        assert_eq!(ds.contains("domain.bbs"), true);
-        assert_eq!(ds.contains("domain.abc"), false);
+        assert!(ds.contains("domain.geek"));
+        assert!(ds.contains("www.domain.geek"));
+        assert!(ds.contains("domain.bbs"));
+        assert!(!ds.contains("domain.abc"));
     }
 }
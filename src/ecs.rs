@@ -0,0 +1,226 @@
+use hickory_proto::op::Edns;
+use hickory_proto::rr::rdata::opt::{ClientSubnet, EdnsCode, EdnsOption};
+use hickory_proto::xfer::DnsRequest;
+use hickory_server::server::Request;
+use ipnet::IpNet;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// The payload size announced when a subnet is added to a query without EDNS
+const MAX_PAYLOAD: u16 = 1232;
+
+tokio::task_local! {
+    /// The subnet sent with the queries of the current lookup
+    static SUBNET: Option<ClientSubnet>;
+}
+
+/// What an upstream is told of the client with EDNS Client Subnet.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum EcsPolicy {
+    /// Send no subnet
+    #[default]
+    Strip,
+    /// Forward the subnet of the client's query
+    Passthrough,
+    /// The subnet of the client's address, truncated to these prefix lengths
+    Client { ipv4_prefix: u8, ipv6_prefix: u8 },
+    /// Always send this subnet
+    Subnet(ClientSubnet),
+}
+
+impl EcsPolicy {
+    pub fn client(ipv4_prefix: u8, ipv6_prefix: u8) -> Option<Self> {
+        (ipv4_prefix <= 32 && ipv6_prefix <= 128).then_some(EcsPolicy::Client {
+            ipv4_prefix,
+            ipv6_prefix,
+        })
+    }
+
+    /// Whether the subnet depends on the client, so responses are cached per subnet.
+    pub fn per_client(&self) -> bool {
+        matches!(self, EcsPolicy::Passthrough | EcsPolicy::Client { .. })
+    }
+
+    /// The subnet sent for the queries of `client`.
+    pub fn subnet(&self, client: Option<&QueryClient>) -> Option<ClientSubnet> {
+        match self {
+            EcsPolicy::Strip => None,
+            EcsPolicy::Passthrough => {
+                let subnet = client?.subnet?;
+                truncate(subnet.addr(), subnet.source_prefix())
+            }
+            EcsPolicy::Client {
+                ipv4_prefix,
+                ipv6_prefix,
+            } => {
+                let addr = client?.addr.to_canonical();
+                // a private address tells nothing about where the client is
+                if !is_global(addr) {
+                    return None;
+                }
+                match addr {
+                    IpAddr::V4(_) => truncate(addr, *ipv4_prefix),
+                    IpAddr::V6(_) => truncate(addr, *ipv6_prefix),
+                }
+            }
+            EcsPolicy::Subnet(subnet) => Some(*subnet),
+        }
+    }
+}
+
+impl FromStr for EcsPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strip" => Ok(EcsPolicy::Strip),
+            "passthrough" => Ok(EcsPolicy::Passthrough),
+            "client" => Ok(EcsPolicy::Client {
+                ipv4_prefix: 24,
+                ipv6_prefix: 56,
+            }),
+            subnet => {
+                let net = subnet.parse::<IpNet>().map_err(|_| subnet.to_string())?;
+                Ok(EcsPolicy::Subnet(net.trunc().into()))
+            }
+        }
+    }
+}
+
+/// Where a query comes from
+#[derive(Debug, Clone, Copy)]
+pub struct QueryClient {
+    pub addr: IpAddr,
+    pub subnet: Option<ClientSubnet>,
+}
+
+impl From<&Request> for QueryClient {
+    fn from(request: &Request) -> Self {
+        let subnet = match request
+            .edns()
+            .and_then(|edns| edns.option(EdnsCode::Subnet))
+        {
+            Some(EdnsOption::Subnet(subnet)) => Some(*subnet),
+            _ => None,
+        };
+        QueryClient {
+            addr: request.src().ip(),
+            subnet,
+        }
+    }
+}
+
+fn truncate(addr: IpAddr, prefix: u8) -> Option<ClientSubnet> {
+    let net = IpNet::new(addr, prefix).ok()?;
+    Some(net.trunc().into())
+}
+
+fn is_global(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(ip) => {
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast())
+        }
+        IpAddr::V6(ip) => {
+            let segment = ip.segments()[0];
+            // unique local fc00::/7 and link local fe80::/10
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || segment & 0xfe00 == 0xfc00
+                || segment & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+/// Run `lookup` with its queries carrying `subnet`.
+pub async fn with_subnet<F: Future>(subnet: Option<ClientSubnet>, lookup: F) -> F::Output {
+    SUBNET.scope(subnet, lookup).await
+}
+
+/// Put the subnet of the current lookup in `request`, replacing any other one.
+pub fn apply(request: &mut DnsRequest) {
+    match SUBNET.try_with(|subnet| *subnet).ok().flatten() {
+        Some(subnet) => {
+            let edns = request.extensions_mut().get_or_insert_with(|| {
+                let mut edns = Edns::new();
+                edns.set_max_payload(MAX_PAYLOAD);
+                edns
+            });
+            edns.options_mut().insert(EdnsOption::Subnet(subnet));
+        }
+        None => {
+            if let Some(edns) = request.extensions_mut() {
+                edns.options_mut().remove(EdnsCode::Subnet);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::op::Message;
+
+    fn client(addr: &str, subnet: Option<&str>) -> QueryClient {
+        QueryClient {
+            addr: addr.parse().unwrap(),
+            subnet: subnet.map(|subnet| subnet.parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn policy_subnets() {
+        let public = client("203.0.113.77", Some("198.51.100.0/24"));
+        assert_eq!(EcsPolicy::Strip.subnet(Some(&public)), None);
+        assert_eq!(
+            EcsPolicy::Passthrough.subnet(Some(&public)),
+            Some("198.51.100.0/24".parse().unwrap())
+        );
+        let policy: EcsPolicy = "client".parse().unwrap();
+        assert_eq!(
+            policy.subnet(Some(&public)),
+            Some("203.0.113.0/24".parse().unwrap())
+        );
+        assert_eq!(
+            policy.subnet(Some(&client("::ffff:203.0.113.77", None))),
+            Some("203.0.113.0/24".parse().unwrap())
+        );
+        assert_eq!(
+            EcsPolicy::client(32, 48)
+                .unwrap()
+                .subnet(Some(&client("2001:db8:1:2::1", None))),
+            Some("2001:db8:1::/48".parse().unwrap())
+        );
+        assert_eq!(policy.subnet(Some(&client("192.168.1.2", None))), None);
+        assert_eq!(policy.subnet(None), None);
+        let policy: EcsPolicy = "192.0.2.1/24".parse().unwrap();
+        assert_eq!(policy.subnet(None), Some("192.0.2.0/24".parse().unwrap()));
+        assert!("nowhere".parse::<EcsPolicy>().is_err());
+        assert!(EcsPolicy::client(33, 56).is_none());
+    }
+
+    #[tokio::test]
+    async fn apply_subnet() {
+        let subnet = |request: &DnsRequest| {
+            request
+                .extensions()
+                .as_ref()
+                .and_then(|edns| edns.option(EdnsCode::Subnet))
+                .cloned()
+        };
+        let mut request = DnsRequest::new(Message::new(), Default::default());
+        with_subnet(Some("192.0.2.0/24".parse().unwrap()), async {
+            apply(&mut request)
+        })
+        .await;
+        assert_eq!(
+            subnet(&request),
+            Some(EdnsOption::Subnet("192.0.2.0/24".parse().unwrap()))
+        );
+        with_subnet(None, async { apply(&mut request) }).await;
+        assert_eq!(subnet(&request), None);
+    }
+}
@@ -0,0 +1,129 @@
+use hickory_proto::op::{Edns, Message};
+use hickory_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+use hickory_proto::xfer::Protocol;
+
+/// The payload size of UDP clients without EDNS, and the least one may announce
+pub const MIN_PAYLOAD: u16 = 512;
+/// Encrypted responses are padded to a multiple of this size (RFC 8467)
+const PADDING_BLOCK: usize = 468;
+
+/// Our OPT record for a query with `query` EDNS, none if it had none. The payload
+/// size is the one of the client, up to `max_payload`.
+pub fn response_edns(query: Option<&Edns>, max_payload: u16) -> Option<Edns> {
+    let query = query?;
+    let mut edns = Edns::new();
+    edns.set_max_payload(query.max_payload().clamp(MIN_PAYLOAD, max_payload))
+        .set_dnssec_ok(query.flags().dnssec_ok)
+        .set_version(0);
+    Some(edns)
+}
+
+/// Whether the query asks for padded responses (RFC 7830)
+pub fn wants_padding(query: Option<&Edns>) -> bool {
+    query.is_some_and(|edns| edns.option(EdnsCode::Padding).is_some())
+}
+
+/// Make `response` fit in what the client receives over `protocol`. A UDP
+/// response too large for its payload size is emptied and truncated, so the
+/// client asks again over TCP. With `padding` over an encrypted protocol the OPT
+/// record is padded to hide the size of the response.
+pub fn fit(response: &mut Message, protocol: Protocol, padding: bool) {
+    let mut size = encoded_len(response);
+    let max_payload = response
+        .extensions()
+        .as_ref()
+        .map_or(MIN_PAYLOAD, Edns::max_payload);
+    if protocol == Protocol::Udp && size > usize::from(max_payload) {
+        response.take_answers();
+        response.take_name_servers();
+        response.take_additionals();
+        response.set_truncated(true);
+        size = encoded_len(response);
+    }
+    if padding
+        && protocol.is_encrypted()
+        && let Some(edns) = response.extensions_mut()
+    {
+        // the option adds its code and length
+        let len = size + 4;
+        let padding = len.next_multiple_of(PADDING_BLOCK) - len;
+        edns.options_mut().insert(EdnsOption::Unknown(
+            EdnsCode::Padding.into(),
+            vec![0; padding],
+        ));
+    }
+}
+
+fn encoded_len(message: &Message) -> usize {
+    message.to_vec().map_or(0, |buf| buf.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::rr::rdata::TXT;
+    use hickory_proto::rr::{Name, RData, Record};
+
+    fn response(txt_len: usize, edns: Option<Edns>) -> Message {
+        let mut message = Message::new();
+        message.add_answer(Record::from_rdata(
+            Name::from_ascii("example.com.").unwrap(),
+            60,
+            RData::TXT(TXT::new(vec!["x".repeat(200); txt_len / 200])),
+        ));
+        if let Some(edns) = edns {
+            message.set_edns(edns);
+        }
+        message
+    }
+
+    fn query_edns(max_payload: u16) -> Edns {
+        let mut edns = Edns::new();
+        edns.set_max_payload(max_payload).set_dnssec_ok(true);
+        edns
+    }
+
+    #[test]
+    fn response_payload() {
+        let edns = response_edns(Some(&query_edns(4096)), 1232).unwrap();
+        assert_eq!(edns.max_payload(), 1232);
+        assert!(edns.flags().dnssec_ok);
+        let edns = response_edns(Some(&query_edns(100)), 1232).unwrap();
+        assert_eq!(edns.max_payload(), 512);
+        assert!(response_edns(None, 1232).is_none());
+    }
+
+    #[test]
+    fn truncate_udp() {
+        let edns = response_edns(Some(&query_edns(4096)), 1232);
+        let mut message = response(1000, edns.clone());
+        fit(&mut message, Protocol::Udp, false);
+        assert!(!message.truncated());
+        assert_eq!(message.answers().len(), 1);
+
+        // the 512 bytes of clients without EDNS
+        let mut message = response(1000, None);
+        fit(&mut message, Protocol::Udp, false);
+        assert!(message.truncated());
+        assert!(message.answers().is_empty());
+
+        let mut message = response(2000, edns.clone());
+        fit(&mut message, Protocol::Tcp, false);
+        assert!(!message.truncated());
+        fit(&mut message, Protocol::Udp, false);
+        assert!(message.truncated());
+        assert!(message.extensions().is_some());
+    }
+
+    #[cfg(feature = "dns-over-tls")]
+    #[test]
+    fn pad_encrypted() {
+        let edns = response_edns(Some(&query_edns(4096)), 1232);
+        let mut message = response(600, edns.clone());
+        fit(&mut message, Protocol::Tls, true);
+        assert_eq!(encoded_len(&message) % PADDING_BLOCK, 0);
+        let mut message = response(600, edns);
+        fit(&mut message, Protocol::Udp, true);
+        assert_ne!(encoded_len(&message) % PADDING_BLOCK, 0);
+    }
+}
@@ -0,0 +1,223 @@
+use crate::config::RuleAction;
+use crate::filter::{self, TagMatch};
+use crate::handler_config::HandlerConfig;
+use hickory_proto::op::{LowerQuery, Query};
+use hickory_proto::rr::rdata::{A, AAAA};
+use hickory_proto::rr::{Name, RData, Record, RecordType};
+use std::fmt::Write;
+use std::net::IpAddr;
+
+/// Describe how the rules route a query of `name` and `record_type`, and what the
+/// response rules of each upstream make of `answers`. Nothing is sent.
+pub fn explain(
+    config: &HandlerConfig,
+    name: &Name,
+    record_type: RecordType,
+    answers: &[IpAddr],
+) -> String {
+    let query = LowerQuery::query(Query::query(name.clone(), record_type));
+    let domain = query.name().to_string();
+    let mut out = String::new();
+    let _ = writeln!(out, "Query {} {}", domain, record_type);
+
+    let _ = writeln!(out, "\nRequest rules:");
+    for (index, rule) in config.request_rules.iter().enumerate() {
+        let _ = writeln!(out, "  #{}", index);
+        let domains = describe_tags(rule.domains.as_deref(), |pattern| {
+            filter::domain_tag(config, &domain, pattern)
+        });
+        let _ = writeln!(out, "    domains: {}", domains.0);
+        let types = match &rule.types {
+            Some(types) => {
+                let matched = types.contains(&record_type);
+                let list: Vec<_> = types.iter().map(ToString::to_string).collect();
+                let verdict = if matched { "match" } else { "no match" };
+                let _ = writeln!(out, "    types [{}] -> {}", list.join(", "), verdict);
+                matched
+            }
+            None => true,
+        };
+        if domains.1 && types {
+            let _ = writeln!(out, "    matched");
+            break;
+        }
+    }
+    let (rule, upstreams) = filter::resolvers(config, &query);
+    match rule {
+        Some(rule) => {
+            let _ = writeln!(out, "Upstreams of rule #{}: {}", rule, upstreams.join(", "));
+        }
+        None => {
+            let _ = writeln!(
+                out,
+                "No rule matched, default upstreams: {}",
+                upstreams.join(", ")
+            );
+        }
+    }
+
+    if answers.is_empty() {
+        let _ = writeln!(
+            out,
+            "\nNo sample answer, the response rules are not evaluated"
+        );
+        return out;
+    }
+    let records: Vec<_> = answers
+        .iter()
+        .map(|ip| {
+            let rdata = match ip {
+                IpAddr::V4(ip) => RData::A(A(*ip)),
+                IpAddr::V6(ip) => RData::AAAA(AAAA(*ip)),
+            };
+            Record::from_rdata(name.clone(), 60, rdata)
+        })
+        .collect();
+    let list: Vec<_> = answers.iter().map(ToString::to_string).collect();
+    let _ = writeln!(out, "\nResponse rules for {}:", list.join(", "));
+    for upstream in &upstreams {
+        let _ = writeln!(out, "  {}", upstream);
+        if !config.resolvers.contains_key(upstream) {
+            let _ = writeln!(out, "    unknown upstream, it is skipped");
+            continue;
+        }
+        let (action, rule) = filter::check_response(config, &domain, upstream, &records);
+        for (index, response_rule) in config.response_rules.iter().enumerate() {
+            let _ = writeln!(out, "    #{}", index);
+            if let Some(rule_upstreams) = &response_rule.upstreams {
+                let matched = rule_upstreams.contains(upstream);
+                let verdict = if matched { "match" } else { "no match" };
+                let _ = writeln!(
+                    out,
+                    "      upstreams [{}] -> {}",
+                    rule_upstreams.join(", "),
+                    verdict
+                );
+            }
+            let ranges = describe_tags(response_rule.ranges.as_deref(), |pattern| {
+                filter::range_tag(config, &records, pattern)
+            });
+            let _ = writeln!(out, "      ranges: {}", ranges.0);
+            let domains = describe_tags(response_rule.domains.as_deref(), |pattern| {
+                filter::domain_tag(config, &domain, pattern)
+            });
+            let _ = writeln!(out, "      domains: {}", domains.0);
+            if rule == Some(index) {
+                break;
+            }
+        }
+        let action = match action {
+            RuleAction::Accept => "accept",
+            RuleAction::Drop => "drop",
+        };
+        match rule {
+            Some(rule) => {
+                let _ = writeln!(out, "    -> {} by rule #{}", action, rule);
+            }
+            None => {
+                let _ = writeln!(out, "    -> {}, no rule matched", action);
+            }
+        }
+    }
+    out
+}
+
+/// How the tag `patterns` of a rule evaluated, and whether any matched
+fn describe_tags<'a>(
+    patterns: Option<&'a [String]>,
+    evaluate: impl Fn(&'a str) -> TagMatch<'a>,
+) -> (String, bool) {
+    let Some(patterns) = patterns else {
+        return ("any -> match".to_string(), true);
+    };
+    let mut matched = false;
+    let described: Vec<_> = patterns
+        .iter()
+        .map(|pattern| {
+            let tag = evaluate(pattern);
+            matched |= tag.matches();
+            let verdict = if tag.matches() { "match" } else { "no match" };
+            if !tag.known {
+                format!("{} (unknown tag) -> {}", pattern, verdict)
+            } else {
+                format!(
+                    "{} ({} {}{}) -> {}",
+                    pattern,
+                    if tag.contains { "in" } else { "not in" },
+                    tag.tag,
+                    if tag.inverted { ", inverted" } else { "" },
+                    verdict
+                )
+            }
+        })
+        .collect();
+    (described.join("; "), matched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConfigBuilder;
+    use std::str::FromStr;
+
+    #[test]
+    fn explain_rules() {
+        let config = HandlerConfig::from(
+            serde_yaml::from_str::<ConfigBuilder>(
+                r#"
+                bind: 127.0.0.1:5300
+                upstreams:
+                    local:
+                        address: [192.0.2.53]
+                        network: udp
+                    remote:
+                        address: [192.0.2.54]
+                        network: udp
+                        default: false
+                domains:
+                    cn:
+                        list: [example.cn]
+                ranges:
+                    cn:
+                        list: [198.51.100.0/24]
+                requests:
+                    - domains: ["!cn"]
+                      types: [AAAA]
+                      upstreams: [remote]
+                responses:
+                    - upstreams: [local]
+                      ranges: ["!cn"]
+                      action: drop
+                "#,
+            )
+            .unwrap()
+            .build()
+            .unwrap(),
+        );
+        let name = Name::from_str("www.example.com").unwrap();
+
+        let out = explain(&config, &name, RecordType::A, &[]);
+        assert!(out.contains("domains: !cn (not in cn, inverted) -> match"));
+        assert!(out.contains("types [AAAA] -> no match"));
+        assert!(out.contains("No rule matched, default upstreams: local"));
+
+        let out = explain(&config, &name, RecordType::AAAA, &[]);
+        assert!(out.contains("Upstreams of rule #0: remote"));
+
+        let out = explain(
+            &config,
+            &name,
+            RecordType::A,
+            &["192.0.2.1".parse().unwrap()],
+        );
+        assert!(out.contains("ranges: !cn (not in cn, inverted) -> match"));
+        assert!(out.contains("-> drop by rule #0"));
+        let out = explain(
+            &config,
+            &name,
+            RecordType::A,
+            &["198.51.100.1".parse().unwrap()],
+        );
+        assert!(out.contains("-> accept, no rule matched"));
+    }
+}
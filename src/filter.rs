@@ -2,21 +2,29 @@ use crate::{
     config::{RequestRule, ResponseRule, RuleAction},
     handler_config::HandlerConfig,
 };
-use hickory_proto::{op::LowerQuery, rr::RecordType};
-use hickory_resolver::lookup::Lookup;
+use hickory_proto::{
+    op::LowerQuery,
+    rr::{Record, RecordType},
+};
 use log::debug;
 
+/// The action on the answers of `upstream_name`, with the index of the response rule
+/// which matched
 pub fn check_response(
     cfg: &HandlerConfig,
     domain: &str,
     upstream_name: &str,
-    resp: &Lookup,
-) -> RuleAction {
-    let answers = resp.records();
-
+    answers: &[Record],
+) -> (RuleAction, Option<usize>) {
     // Drop empty response
     if answers.is_empty() {
-        return RuleAction::Drop;
+        return (RuleAction::Drop, None);
+    }
+    // Drop forged answers, another upstream may have the genuine ones
+    #[cfg(feature = "dnssec")]
+    if crate::dnssec::is_bogus(answers) {
+        debug!("Drop bogus answers of {} from {}", domain, upstream_name);
+        return (RuleAction::Drop, None);
     }
 
     let check_upstream = |rule: &ResponseRule| {
@@ -30,48 +38,23 @@ pub fn check_response(
         rule.ranges
             .as_ref()
             .map(|r| {
-                r.iter().any(|range_pattern| {
-                    // Process the leading `!`
-                    let range_name = range_pattern.trim_start_matches('!');
-                    let toggle = (range_pattern.len() - range_name.len()) % 2 == 1;
-
-                    // See if the range contains the IP
-                    let range = cfg.ranges.get(range_name);
-                    range
-                        .map(|range| {
-                            answers
-                                .iter()
-                                .filter_map(|rec| match rec.record_type() {
-                                    RecordType::A => {
-                                        let ip = rec.data().as_a().unwrap().0;
-                                        Some(range.contains((ip).into()))
-                                    }
-                                    RecordType::AAAA => {
-                                        let ip = rec.data().as_aaaa().unwrap().0;
-                                        Some(range.contains((ip).into()))
-                                    }
-                                    _ => None,
-                                })
-                                .next()
-                                .unwrap_or(false)
-                                ^ toggle // toggle result according to the number of !
-                        })
-                        .unwrap_or(false)
-                })
+                r.iter()
+                    .any(|range_pattern| range_tag(cfg, answers, range_pattern).matches())
             })
             .unwrap_or(true) // No ranges field means matching all ranges
     };
 
-    cfg.response_rules
-        .iter()
-        .find(|rule| {
-            check_upstream(rule) && check_ranges(rule) && check_domains(cfg, domain, &rule.domains)
-        })
-        .map(|rule| rule.action)
-        .unwrap_or(RuleAction::Accept)
+    let rule = cfg.response_rules.iter().enumerate().find(|(_, rule)| {
+        check_upstream(rule) && check_ranges(rule) && check_domains(cfg, domain, &rule.domains)
+    });
+    match rule {
+        Some((index, rule)) => (rule.action, Some(index)),
+        None => (RuleAction::Accept, None),
+    }
 }
 
-pub fn resolvers(cfg: &HandlerConfig, query: &LowerQuery) -> Vec<String> {
+/// The upstreams of `query`, with the index of the request rule which matched
+pub fn resolvers(cfg: &HandlerConfig, query: &LowerQuery) -> (Option<usize>, Vec<String>) {
     let name = query.name().to_string();
 
     let check_type = |rule: &RequestRule| {
@@ -84,35 +67,91 @@ pub fn resolvers(cfg: &HandlerConfig, query: &LowerQuery) -> Vec<String> {
     let rule = cfg
         .request_rules
         .iter()
-        .find(|r| check_domains(cfg, &name, &r.domains) && check_type(r));
+        .enumerate()
+        .find(|(_, r)| check_domains(cfg, &name, &r.domains) && check_type(r));
 
-    if let Some(rule) = rule {
+    if let Some((index, rule)) = rule {
         debug!("Query {} matches rule {:?}", name, rule);
-        rule.upstreams.clone()
+        (Some(index), rule.upstreams.clone())
     } else {
         debug!("No rule matches for {}. Use defaults.", name);
         // If no rule matches, use defaults
-        cfg.defaults.to_vec()
+        (None, cfg.defaults.to_vec())
     }
 }
 
 fn check_domains(cfg: &HandlerConfig, domain: &str, domains: &Option<Vec<String>>) -> bool {
-    let name = domain.trim_end_matches(".");
     domains
         .as_ref()
         .map(|d| {
-            d.iter().any(|domains_pattern| {
-                // Process the leading `!`
-                let domains_tag = domains_pattern.trim_start_matches('!');
-                let toggle = (domains_pattern.len() - domains_tag.len()) % 2 == 1;
-                let domains = cfg.domains.get(domains_tag);
-                domains
-                    .map(|domains| {
-                        (domains.regex_set.is_match(&name) || domains.suffix.contains(&name))
-                            ^ toggle
-                    })
-                    .unwrap_or(false)
-            })
+            d.iter()
+                .any(|domains_pattern| domain_tag(cfg, domain, domains_pattern).matches())
         })
         .unwrap_or(true) // No domains field means matching all domains
 }
+
+/// How a domains or ranges tag of a rule evaluated
+#[derive(Debug, PartialEq)]
+pub struct TagMatch<'a> {
+    pub tag: &'a str,
+    /// An odd number of leading `!`
+    pub inverted: bool,
+    /// Whether there are domains or ranges with this name, an unknown tag matches nothing
+    pub known: bool,
+    pub contains: bool,
+}
+
+impl TagMatch<'_> {
+    pub fn matches(&self) -> bool {
+        self.known && (self.contains ^ self.inverted)
+    }
+}
+
+fn parse_tag(pattern: &str) -> (&str, bool) {
+    // Process the leading `!`
+    let tag = pattern.trim_start_matches('!');
+    (tag, (pattern.len() - tag.len()) % 2 == 1)
+}
+
+/// Evaluate the domains `pattern` for `domain`
+pub fn domain_tag<'a>(cfg: &HandlerConfig, domain: &str, pattern: &'a str) -> TagMatch<'a> {
+    let name = domain.trim_end_matches(".");
+    let (tag, inverted) = parse_tag(pattern);
+    let domains = cfg.domains.get(tag);
+    TagMatch {
+        tag,
+        inverted,
+        known: domains.is_some(),
+        contains: domains.is_some_and(|domains| {
+            domains.regex_set.is_match(name) || domains.suffix.contains(name)
+        }),
+    }
+}
+
+/// Evaluate the ranges `pattern` for the first address in `answers`
+pub fn range_tag<'a>(cfg: &HandlerConfig, answers: &[Record], pattern: &'a str) -> TagMatch<'a> {
+    let (tag, inverted) = parse_tag(pattern);
+    let range = cfg.ranges.get(tag);
+    TagMatch {
+        tag,
+        inverted,
+        known: range.is_some(),
+        contains: range.is_some_and(|range| {
+            answers
+                .iter()
+                .filter_map(|rec| match rec.record_type() {
+                    RecordType::A => {
+                        let ip = rec.data().as_a().unwrap().0;
+                        Some(range.contains((ip).into()))
+                    }
+                    RecordType::AAAA => {
+                        let ip = rec.data().as_aaaa().unwrap().0;
+                        Some(range.contains((ip).into()))
+                    }
+                    _ => None,
+                })
+                .next()
+                .unwrap_or(false)
+        }),
+    }
+}
@@ -1,5 +1,24 @@
-use crate::{config::RuleAction, filter, handler_config::HandlerConfig};
-use hickory_proto::{op::LowerQuery, rr::Record};
+use crate::config::ForwardMode;
+#[cfg(feature = "dnssec")]
+use crate::dnssec;
+use crate::dnstap;
+use crate::ecs::QueryClient;
+use crate::edns;
+use crate::metrics::Outcome;
+use crate::querylog::{Dropped, Route};
+use crate::{
+    config::RuleAction,
+    filter,
+    handler_config::{HandlerConfig, SharedConfig},
+};
+use hickory_proto::{
+    ProtoError, ProtoErrorKind,
+    op::{Edns, LowerQuery, Message},
+    rr::rdata::opt::EdnsCode,
+    rr::{Record, RecordType},
+};
+#[cfg(feature = "dnssec")]
+use hickory_proto::{dnssec::rdata::DNSSECRData, rr::RData};
 use hickory_resolver::{ResolveError, lookup::Lookup};
 use hickory_server::{
     authority::MessageResponseBuilder,
@@ -7,15 +26,21 @@ use hickory_server::{
     server::{Request, RequestHandler, ResponseHandler, ResponseInfo},
 };
 use log::debug;
-use std::time::Duration;
-use tokio::runtime::{Builder, Runtime};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::runtime::{Builder, Handle, Runtime};
 
 #[derive(Debug)]
 struct RequestResult {
     answers: Option<Lookup>,
     name_servers: Option<Vec<Record>>,
     soa: Option<Vec<Record>>,
+    additionals: Option<Vec<Record>>,
     code: ResponseCode,
+    /// The whole response of an upstream when forwarding raw messages
+    response: Option<Message>,
+    /// Whether the answers were proven secure, unknown when the upstream doesn't validate
+    authentic_data: Option<bool>,
 }
 
 #[allow(dead_code)]
@@ -25,7 +50,17 @@ impl RequestResult {
             answers: None,
             name_servers: None,
             soa: None,
+            additionals: None,
             code,
+            response: None,
+            authentic_data: None,
+        }
+    }
+    pub fn from_response(response: Message) -> Self {
+        Self {
+            code: response.response_code(),
+            response: Some(response),
+            ..Self::new_with_code(ResponseCode::NoError)
         }
     }
     pub fn set_answers(&mut self, answers: Lookup) {
@@ -37,93 +72,280 @@ impl RequestResult {
     pub fn set_soa(&mut self, soa: Vec<Record>) {
         self.soa = Some(soa);
     }
+    pub fn set_additionals(&mut self, additionals: Vec<Record>) {
+        self.additionals = Some(additionals);
+    }
+    pub fn set_authentic_data(&mut self, authentic_data: bool) {
+        self.authentic_data = Some(authentic_data);
+    }
 }
 
 /// DNS Request Handler
 #[derive(Debug)]
 pub struct Handler {
     //pub counter: Arc<AtomicU64>,
-    config: HandlerConfig,
-    rt: Runtime,
+    config: SharedConfig,
+    rt: Option<Runtime>,
 }
 impl Handler {
     /// Create handler from app config.
     pub fn new(cfg: HandlerConfig) -> Self {
         Handler {
-            rt: Builder::new_multi_thread()
-                .thread_name("handler-worker")
-                .worker_threads(cfg.resolvers.len() * 2)
-                .enable_all()
-                .build()
-                .unwrap(),
-            config: cfg,
+            rt: Some(
+                Builder::new_multi_thread()
+                    .thread_name("handler-worker")
+                    .worker_threads(cfg.resolvers.len() * 2)
+                    .enable_all()
+                    .build()
+                    .unwrap(),
+            ),
+            config: Arc::new(RwLock::new(Arc::new(cfg))),
         }
     }
 
+    /// The configuration of the handler, which may be replaced while it runs
+    pub fn shared_config(&self) -> SharedConfig {
+        self.config.clone()
+    }
+
+    fn config(&self) -> Arc<HandlerConfig> {
+        self.config.read().unwrap().clone()
+    }
+
+    fn runtime(&self) -> &Handle {
+        self.rt.as_ref().unwrap().handle()
+    }
+
     /// Handle request, returning ResponseInfo if response was successfully sent, or an error.
-    async fn do_handle_request(&self, request: &Request) -> Result<RequestResult, ResolveError> {
+    async fn do_handle_request(
+        &self,
+        config: &HandlerConfig,
+        request: &Request,
+        route: &mut Route,
+    ) -> Result<RequestResult, ResolveError> {
         let query = &request.queries()[0];
         debug!("DNS requests are forwarded to [{}].", query);
         // make sure the request is a query and the message type is a query
         if request.op_code() != OpCode::Query || request.message_type() != MessageType::Query {
             return Ok(RequestResult::new_with_code(ResponseCode::Refused));
         }
-        self.lookup(query).await
+        let client = QueryClient::from(request);
+        match config.forward {
+            ForwardMode::Resolve => self.lookup(config, query, client, route).await,
+            ForwardMode::Raw => self.forward(config, request, query, client, route).await,
+        }
+    }
+
+    /// Relay the request to the upstreams, returning the first response the rules accept.
+    async fn forward(
+        &self,
+        config: &HandlerConfig,
+        request: &Request,
+        query: &LowerQuery,
+        client: QueryClient,
+        route: &mut Route,
+    ) -> Result<RequestResult, ResolveError> {
+        let message = Arc::new(upstream_message(request, query));
+        let domain = query.name().to_string();
+        let mut join_set = tokio::task::JoinSet::new();
+        let (rule, resolvers) = filter::resolvers(config, query);
+        route.rule = rule;
+        route.upstreams = resolvers.clone();
+        for name in resolvers {
+            if let Some(resolver) = config.resolver(&name) {
+                let message = message.clone();
+                let metrics = config.metrics.clone();
+                let dnstap = config.dnstap.clone();
+                metrics.upstream_request(&name);
+                join_set.spawn_on(
+                    async move {
+                        let started = Instant::now();
+                        let response = tokio::time::timeout(
+                            Duration::from_secs(5),
+                            dnstap::tap(dnstap, &name, resolver.forward(&message, Some(&client))),
+                        )
+                        .await;
+                        let outcome = response.as_ref().ok().map(|response| match response {
+                            Ok(_) => Outcome::Response,
+                            Err(e) => Outcome::from(e),
+                        });
+                        metrics.upstream_response(&name, started.elapsed(), outcome);
+                        (response, name)
+                    },
+                    self.runtime(),
+                );
+            }
+        }
+        // a response without answers is relayed when none is accepted
+        let mut negative: Option<(RequestResult, String)> = None;
+        let mut accepted = None;
+        while let Some(res) = join_set.join_next().await {
+            let Ok((Ok(Ok(response)), name)) = res else {
+                continue;
+            };
+            if response.answers().is_empty() {
+                #[cfg(feature = "dnssec")]
+                if dnssec::is_bogus(response.name_servers()) {
+                    debug!("Drop bogus response from {}", name);
+                    route.dropped.push(Dropped {
+                        upstream: name,
+                        rule: None,
+                    });
+                    continue;
+                }
+                if negative
+                    .as_ref()
+                    .is_none_or(|(negative, _)| negative.code == ResponseCode::ServFail)
+                {
+                    negative = Some((self.relayed(config, &name, request, response), name));
+                }
+                continue;
+            }
+            match filter::check_response(config, &domain, &name, response.answers()) {
+                (RuleAction::Accept, _) => {
+                    debug!("Use response from {}", name);
+                    config.metrics.upstream_won(&name);
+                    accepted = Some((self.relayed(config, &name, request, response), name));
+                    break;
+                }
+                (RuleAction::Drop, rule) => {
+                    if let Some(rule) = rule {
+                        config.metrics.rule_dropped(rule);
+                    }
+                    route.dropped.push(Dropped {
+                        upstream: name,
+                        rule,
+                    })
+                }
+            }
+        }
+        join_set.abort_all();
+        join_set.detach_all();
+        match accepted.or(negative) {
+            Some((result, name)) => {
+                route.winner = Some(name);
+                Ok(result)
+            }
+            None => Ok(RequestResult::new_with_code(ResponseCode::NXDomain)),
+        }
+    }
+
+    /// The result relaying `response` of the upstream `name`
+    fn relayed(
+        &self,
+        config: &HandlerConfig,
+        name: &str,
+        request: &Request,
+        response: Message,
+    ) -> RequestResult {
+        RequestResult {
+            authentic_data: authentic_data(config, name, request, &response),
+            ..RequestResult::from_response(response)
+        }
     }
 
     /// Lookup for anything else (NXDOMAIN)
-    async fn lookup(&self, query: &LowerQuery) -> Result<RequestResult, ResolveError> {
+    async fn lookup(
+        &self,
+        config: &HandlerConfig,
+        query: &LowerQuery,
+        client: QueryClient,
+        route: &mut Route,
+    ) -> Result<RequestResult, ResolveError> {
         //self.counter.fetch_add(1, Ordering::SeqCst);
-        let config = &self.config;
-        let resolvers = filter::resolvers(config, query);
+        let (rule, resolvers) = filter::resolvers(config, query);
+        route.rule = rule;
+        route.upstreams = resolvers.clone();
         let mut join_set = tokio::task::JoinSet::new();
         resolvers.into_iter().for_each(|name| {
-            if let Some(resolver) = config.resolvers.get(&name).cloned() {
+            if let Some(resolver) = config.resolver(&name) {
                 let domain = query.name().to_string();
                 let query_type = query.query_type();
+                let metrics = config.metrics.clone();
+                let dnstap = config.dnstap.clone();
+                metrics.upstream_request(&name);
                 join_set.spawn_on(
-                    tokio::time::timeout(Duration::from_secs(5), async move {
-                        let lookup = resolver.resolve(&domain, query_type).await;
+                    async move {
+                        let started = Instant::now();
+                        let lookup = tokio::time::timeout(
+                            Duration::from_secs(5),
+                            dnstap::tap(
+                                dnstap,
+                                &name,
+                                resolver.resolve_with_sections(&domain, query_type, Some(&client)),
+                            ),
+                        )
+                        .await;
+                        let outcome = lookup.as_ref().ok().map(|lookup| match lookup {
+                            Ok(_) => Outcome::Response,
+                            Err(e) => Outcome::from(e),
+                        });
+                        metrics.upstream_response(&name, started.elapsed(), outcome);
                         (lookup, name, domain)
-                    }),
-                    self.rt.handle(),
+                    },
+                    self.runtime(),
                 );
             }
         });
         let mut lookup_result = None;
         while let Some(res) = join_set.join_next().await {
-            match res {
-                Ok(lookup) => match lookup {
-                    Ok((lookup, name, domain)) => {
-                        match lookup {
-                            Ok(lookup) => {
-                                match filter::check_response(config, &domain, &name, &lookup) {
-                                    RuleAction::Accept => {
-                                        debug!("Use result from {}", name);
-                                        let mut result =
-                                            RequestResult::new_with_code(ResponseCode::NoError);
-                                        result.set_answers(lookup);
-                                        lookup_result = Some(result);
-                                        break;
-                                    }
-                                    RuleAction::Drop => (),
+            if let Ok((Ok(lookup), name, domain)) = res {
+                match lookup {
+                    Ok((lookup, sections)) => {
+                        match filter::check_response(config, &domain, &name, lookup.records()) {
+                            (RuleAction::Accept, _) => {
+                                debug!("Use result from {}", name);
+                                config.metrics.upstream_won(&name);
+                                route.winner = Some(name.clone());
+                                let mut result =
+                                    RequestResult::new_with_code(ResponseCode::NoError);
+                                #[cfg(feature = "dnssec")]
+                                if config.validates(&name) {
+                                    result.set_authentic_data(dnssec::is_secure(lookup.records()));
                                 }
+                                result.set_answers(lookup);
+                                result.set_name_server(sections.name_servers);
+                                result.set_additionals(sections.additionals);
+                                lookup_result = Some(result);
+                                break;
                             }
-                            Err(e) => match e.into_soa() {
-                                Some(soa) => {
-                                    let mut result =
-                                        RequestResult::new_with_code(ResponseCode::NXDomain);
-                                    result.set_soa(vec![soa.into_record_of_rdata()]);
-                                    lookup_result = Some(result);
+                            (RuleAction::Drop, rule) => {
+                                if let Some(rule) = rule {
+                                    config.metrics.rule_dropped(rule);
                                 }
-                                None => (),
-                            },
-                        };
+                                route.dropped.push(Dropped {
+                                    upstream: name,
+                                    rule,
+                                })
+                            }
+                        }
                     }
-                    _ => {}
-                },
-                _ => {}
-            };
+                    Err(e) => {
+                        // a forged negative answer must not win the race
+                        #[cfg(feature = "dnssec")]
+                        if dnssec::is_bogus_error(&e) {
+                            debug!("Drop bogus response from {}", name);
+                            route.dropped.push(Dropped {
+                                upstream: name,
+                                rule: None,
+                            });
+                            continue;
+                        }
+                        // NXDOMAIN, or NODATA which keeps the NOERROR code
+                        if let Some(ProtoErrorKind::NoRecordsFound {
+                            soa: Some(soa),
+                            response_code,
+                            ..
+                        }) = e.proto().map(ProtoError::kind)
+                        {
+                            let mut result = RequestResult::new_with_code(*response_code);
+                            result.set_soa(vec![soa.clone().into_record_of_rdata()]);
+                            route.winner = Some(name);
+                            lookup_result = Some(result);
+                        }
+                    }
+                };
+            }
         }
         join_set.abort_all();
         join_set.detach_all();
@@ -134,6 +356,15 @@ impl Handler {
     }
 }
 
+// the last reference may be dropped by an async task, where a runtime can't block
+impl Drop for Handler {
+    fn drop(&mut self) {
+        if let Some(rt) = self.rt.take() {
+            rt.shutdown_background();
+        }
+    }
+}
+
 #[async_trait::async_trait]
 impl RequestHandler for Handler {
     async fn handle_request<R: ResponseHandler>(
@@ -141,9 +372,18 @@ impl RequestHandler for Handler {
         request: &Request,
         mut response: R,
     ) -> ResponseInfo {
+        let config = self.config();
+        let started = Instant::now();
+        let query_time = SystemTime::now();
+        if let Some(dnstap) = &config.dnstap {
+            dnstap.client_query(request, query_time);
+        }
+        let mut route = Route::default();
         // try to handle request
-        let result = if request.queries().len() > 0 {
-            match self.do_handle_request(request).await {
+        let result = if request.edns().is_some_and(|edns| edns.version() > 0) {
+            RequestResult::new_with_code(ResponseCode::BADVERS)
+        } else if !request.queries().is_empty() {
+            match self.do_handle_request(&config, request, &mut route).await {
                 Ok(info) => info,
                 Err(e) => {
                     debug!("Error in RequestHandler:{:#?}", e);
@@ -153,14 +393,491 @@ impl RequestHandler for Handler {
         } else {
             RequestResult::new_with_code(ResponseCode::FormErr)
         };
-        let answers = result.answers.as_ref().map(Lookup::records).unwrap_or(&[]);
-        let name_servers = result.name_servers.unwrap_or_default();
-        let soa = result.soa.unwrap_or_default();
-        let builder = MessageResponseBuilder::from_message_request(request);
-        let mut header = Header::response_from_request(request.header());
-        header.set_response_code(result.code);
-        header.set_recursion_available(true);
-        let message = builder.build(header, answers, name_servers.iter(), soa.iter(), &[]);
+        let mut reply = Message::new();
+        if let Some(upstream_response) = result.response {
+            let mut header = *upstream_response.header();
+            header.set_id(request.id());
+            // the upstream's own AD bit when it wasn't validated here
+            let authentic_data = result.authentic_data.unwrap_or(header.authentic_data());
+            header
+                .set_authentic_data(authentic_data && wants_authentic_data(request))
+                .set_authoritative(false);
+            reply.set_header(header);
+            let answers = upstream_response.answers();
+            reply.add_answers(answers.iter().cloned());
+            // a negative response is proven by its authority section
+            if !config.minimal_responses || answers.is_empty() {
+                reply.add_name_servers(upstream_response.name_servers().iter().cloned());
+            }
+            if !config.minimal_responses {
+                reply.add_additionals(upstream_response.additionals().iter().cloned());
+            }
+            // the options of the upstream, such as the scope of a client subnet
+            if let Some(mut edns) = response_edns(&config, request)
+                && let Some(upstream_edns) = upstream_response.extensions()
+            {
+                for (code, option) in upstream_edns.options().as_ref() {
+                    if *code != EdnsCode::Padding {
+                        edns.options_mut().insert(option.clone());
+                    }
+                }
+                reply.set_edns(edns);
+            }
+        } else {
+            let records = result.answers.as_ref().map(Lookup::records).unwrap_or(&[]);
+            let (answers, mut additionals) = split_answers(&request.queries()[0], records);
+            let mut name_servers = result.name_servers.unwrap_or_default();
+            if config.minimal_responses {
+                name_servers.clear();
+                additionals.clear();
+            } else {
+                for record in result.additionals.unwrap_or_default() {
+                    if !additionals.contains(&record) {
+                        additionals.push(record);
+                    }
+                }
+            }
+            let mut header = Header::response_from_request(request.header());
+            header.set_response_code(result.code);
+            header.set_recursion_available(true);
+            header.set_authentic_data(
+                result.authentic_data.unwrap_or(false) && wants_authentic_data(request),
+            );
+            reply
+                .set_header(header)
+                .add_answers(answers)
+                .add_name_servers(name_servers)
+                .add_name_servers(result.soa.unwrap_or_default())
+                .add_additionals(additionals);
+        }
+        if reply.extensions().is_none()
+            && let Some(edns) = response_edns(&config, request)
+        {
+            reply.set_edns(edns);
+        }
+        reply.add_queries(
+            request
+                .queries()
+                .iter()
+                .map(|query| query.original().clone()),
+        );
+        edns::fit(
+            &mut reply,
+            request.protocol(),
+            edns::wants_padding(request.edns()),
+        );
+        config.metrics.query(
+            request
+                .queries()
+                .first()
+                .map(|query| query.query_type().to_string()),
+            reply.response_code(),
+        );
+        if let Some(dnstap) = &config.dnstap {
+            dnstap.client_response(request, query_time, &reply);
+        }
+        if let Some(query_log) = &config.query_log {
+            query_log.log(request, route, &reply, started.elapsed());
+        }
+        let mut builder = MessageResponseBuilder::from_message_request(request);
+        if let Some(edns) = reply.extensions() {
+            builder.edns(edns.clone());
+        }
+        let message = builder.build(
+            *reply.header(),
+            reply.answers(),
+            reply.name_servers(),
+            &[],
+            reply.additionals(),
+        );
         response.send_response(message).await.unwrap()
     }
 }
+
+fn response_edns(config: &HandlerConfig, request: &Request) -> Option<Edns> {
+    edns::response_edns(request.edns(), config.max_udp_payload)
+}
+
+/// Split the records of a lookup into the answers to `query` and the records hickory
+/// took from the additional section, such as the addresses of NS and SRV targets.
+fn split_answers(query: &LowerQuery, records: &[Record]) -> (Vec<Record>, Vec<Record>) {
+    let query_type = query.query_type();
+    records.iter().cloned().partition(|record| {
+        let record_type = match record.data() {
+            #[cfg(feature = "dnssec")]
+            RData::DNSSEC(DNSSECRData::RRSIG(rrsig)) => rrsig.type_covered(),
+            _ => record.record_type(),
+        };
+        query_type == RecordType::ANY
+            || record_type == query_type
+            || record_type == RecordType::CNAME
+    })
+}
+
+/// Whether the relayed `response` of the upstream `name` is proven secure, unknown
+/// when it wasn't validated
+#[cfg(feature = "dnssec")]
+fn authentic_data(
+    config: &HandlerConfig,
+    name: &str,
+    request: &Request,
+    response: &Message,
+) -> Option<bool> {
+    // responses to queries with checking disabled were not validated
+    if !config.validates(name) || request.checking_disabled() {
+        return None;
+    }
+    let records = match response.answers() {
+        [] => response.name_servers(),
+        answers => answers,
+    };
+    Some(dnssec::is_secure(records))
+}
+
+#[cfg(not(feature = "dnssec"))]
+fn authentic_data(_: &HandlerConfig, _: &str, _: &Request, _: &Message) -> Option<bool> {
+    None
+}
+
+/// Whether the client understands the AD bit, which it tells by setting DO or AD
+/// in its query (RFC 6840 section 5.8).
+fn wants_authentic_data(request: &Request) -> bool {
+    request.header().authentic_data() || request.edns().is_some_and(|edns| edns.flags().dnssec_ok)
+}
+
+/// The client's query with a fresh ID, keeping its flags, EDNS options and
+/// additional records.
+fn upstream_message(request: &Request, query: &LowerQuery) -> Message {
+    let mut header = *request.header();
+    header.set_id(rand::random());
+    let mut message = Message::new();
+    message
+        .set_header(header)
+        .add_query(query.original().clone())
+        .add_additionals(
+            request
+                .additionals()
+                .iter()
+                // the signatures of the client's message don't hold for this one
+                .filter(|record| {
+                    !matches!(record.record_type(), RecordType::SIG | RecordType::TSIG)
+                })
+                .cloned(),
+        );
+    if let Some(edns) = request.edns() {
+        message.set_edns(edns.clone());
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{
+        ConfigBuilder, NetworkType, RequestRuleConfig, ResponseRule, RuleAction, UpstreamConfig,
+    };
+    use crate::resolver::UpstreamResolver;
+    use hickory_proto::rr::rdata::{A, SOA};
+    use hickory_proto::rr::{Name, RData};
+    use hickory_server::ServerFuture;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use tokio::net::UdpSocket;
+
+    /// Answers every query with `respond`, after `delay`
+    #[derive(Debug)]
+    struct MockUpstream {
+        delay: Duration,
+        validates: bool,
+        respond: fn(&Message) -> Message,
+    }
+
+    #[async_trait::async_trait]
+    impl UpstreamResolver for MockUpstream {
+        async fn forward(
+            &self,
+            message: &Message,
+            _client: Option<&QueryClient>,
+        ) -> Result<Message, ProtoError> {
+            tokio::time::sleep(self.delay).await;
+            Ok((self.respond)(message))
+        }
+
+        fn validates(&self) -> bool {
+            self.validates
+        }
+    }
+
+    fn upstream(delay: u64, respond: fn(&Message) -> Message) -> MockUpstream {
+        MockUpstream {
+            delay: Duration::from_millis(delay),
+            validates: false,
+            respond,
+        }
+    }
+
+    fn response(request: &Message, code: ResponseCode) -> Message {
+        let mut response = Message::new();
+        response
+            .set_id(request.id())
+            .set_message_type(MessageType::Response)
+            .set_recursion_desired(request.recursion_desired())
+            .set_recursion_available(true)
+            .set_response_code(code)
+            .add_queries(request.queries().to_vec());
+        response
+    }
+
+    fn a_record(request: &Message) -> Record {
+        let name = request.queries()[0].name().clone();
+        Record::from_rdata(name, 60, RData::A(A::new(192, 0, 2, 1)))
+    }
+
+    fn soa_record() -> Record {
+        let name = Name::from_str("example.com.").unwrap();
+        let soa = SOA::new(name.clone(), name.clone(), 1, 3600, 600, 86400, 60);
+        Record::from_rdata(name, 60, RData::SOA(soa))
+    }
+
+    /// A handler of `config` with `upstreams` as its only upstreams
+    async fn serve(
+        config: ConfigBuilder,
+        upstreams: Vec<(&str, MockUpstream)>,
+    ) -> (SocketAddr, ServerFuture<Handler>) {
+        let config = upstreams.iter().fold(config, |config, (name, _)| {
+            config.upstream(*name, UpstreamConfig::new(NetworkType::Udp, ["192.0.2.53"]))
+        });
+        let config = upstreams.into_iter().fold(
+            HandlerConfig::from(config.build().unwrap()),
+            |config, (name, upstream)| config.with_resolver(name, upstream),
+        );
+        serve_handler(config).await
+    }
+
+    async fn serve_handler(config: HandlerConfig) -> (SocketAddr, ServerFuture<Handler>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        let mut server = ServerFuture::new(Handler::new(config));
+        server.register_socket(socket);
+        (address, server)
+    }
+
+    fn builder(forward: ForwardMode) -> ConfigBuilder {
+        ConfigBuilder::new("127.0.0.1:0".parse().unwrap()).forward(forward)
+    }
+
+    /// A query of `name` for A records, changed by `modify`
+    fn query(name: &str, modify: impl FnOnce(&mut Message)) -> Message {
+        let mut message = Message::new();
+        message
+            .set_id(rand::random())
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true)
+            .add_query(hickory_proto::op::Query::query(
+                Name::from_str(name).unwrap(),
+                RecordType::A,
+            ));
+        modify(&mut message);
+        message
+    }
+
+    async fn ask(address: SocketAddr, message: &Message) -> Message {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket
+            .send_to(&message.to_vec().unwrap(), address)
+            .await
+            .unwrap();
+        let mut buf = [0u8; 4096];
+        let len = tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = Message::from_vec(&buf[..len]).unwrap();
+        assert_eq!(response.id(), message.id());
+        response
+    }
+
+    #[tokio::test]
+    async fn custom_resolver() {
+        for forward in [ForwardMode::Raw, ForwardMode::Resolve] {
+            // the upstream of the config isn't asked for A records
+            let config = builder(forward)
+                .upstream(
+                    "unreachable",
+                    UpstreamConfig::new(NetworkType::Udp, ["192.0.2.53"]),
+                )
+                .request(RequestRuleConfig {
+                    domains: None,
+                    types: Some(vec!["A".to_string()]),
+                    upstreams: vec!["static".to_string()],
+                })
+                .build()
+                .unwrap();
+            let answering = upstream(0, |request| {
+                let mut response = response(request, ResponseCode::NoError);
+                response.add_answer(a_record(request));
+                response
+            });
+            let config = HandlerConfig::from(config).with_resolver("static", answering);
+            let (address, mut server) = serve_handler(config).await;
+            let response = ask(address, &query("example.com.", |_| ())).await;
+            assert_eq!(response.answers()[0].data().to_string(), "192.0.2.1");
+            let _ = server.shutdown_gracefully().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn raw_forward() {
+        use hickory_proto::rr::rdata::NULL;
+
+        let failing = upstream(0, |request| response(request, ResponseCode::ServFail));
+        let nodata = upstream(50, |request| {
+            let mut response = response(request, ResponseCode::NoError);
+            response
+                .set_authoritative(true)
+                .set_authentic_data(true)
+                .add_name_server(soa_record());
+            response
+        });
+        let (address, mut server) = serve(
+            builder(ForwardMode::Raw),
+            vec![("failing", failing), ("nodata", nodata)],
+        )
+        .await;
+        // a negative answer is relayed rather than the failure which came first
+        let relayed = ask(address, &query("example.com.", |_| ())).await;
+        assert_eq!(relayed.response_code(), ResponseCode::NoError);
+        assert_eq!(relayed.name_servers()[0].record_type(), RecordType::SOA);
+        // this server isn't authoritative, and AD is only for the clients which ask for it
+        assert!(!relayed.authoritative());
+        assert!(!relayed.authentic_data());
+        let with_ad = query("example.com.", |message| {
+            message.set_authentic_data(true);
+        });
+        assert!(ask(address, &with_ad).await.authentic_data());
+        let _ = server.shutdown_gracefully().await;
+
+        let dropped = upstream(0, |request| {
+            let mut response = response(request, ResponseCode::NoError);
+            response.add_answer(a_record(request));
+            response
+        });
+        let accepted = upstream(50, |request| {
+            let mut response = response(request, ResponseCode::NoError);
+            // the signature of the client's query isn't relayed
+            if !request
+                .additionals()
+                .iter()
+                .any(|record| record.record_type() == RecordType::TSIG)
+            {
+                let name = request.queries()[0].name().clone();
+                response.add_answer(Record::from_rdata(name, 60, RData::A(A::new(192, 0, 2, 2))));
+            }
+            response
+        });
+        let config = builder(ForwardMode::Raw).response(ResponseRule {
+            upstreams: Some(vec!["dropped".to_string()]),
+            ranges: None,
+            domains: None,
+            action: RuleAction::Drop,
+        });
+        let (address, mut server) =
+            serve(config, vec![("dropped", dropped), ("accepted", accepted)]).await;
+        let signed = query("example.com.", |message| {
+            // hmac-sha256., time signed, fudge, MAC, original ID, error and other data
+            let mut tsig = b"\x0bhmac-sha256\x00".to_vec();
+            tsig.extend_from_slice(&[0, 0, 0x65, 0, 0, 0, 1, 0x2c, 0, 32]);
+            tsig.extend_from_slice(&[0; 32]);
+            tsig.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+            let rdata = RData::Unknown {
+                code: RecordType::TSIG,
+                rdata: NULL::with(tsig),
+            };
+            message.add_additional(Record::from_rdata(
+                Name::from_str("key.").unwrap(),
+                0,
+                rdata,
+            ));
+        });
+        // the response of the upstream comes back with the ID of the client
+        let relayed = ask(address, &signed).await;
+        assert_eq!(relayed.answers()[0].data().to_string(), "192.0.2.2");
+        let _ = server.shutdown_gracefully().await;
+    }
+
+    #[cfg(feature = "dnssec")]
+    #[tokio::test]
+    async fn authentic_data() {
+        use hickory_proto::dnssec::Proof;
+
+        for forward in [ForwardMode::Raw, ForwardMode::Resolve] {
+            let secure = MockUpstream {
+                validates: true,
+                ..upstream(0, |request| {
+                    let mut answer = a_record(request);
+                    answer.set_proof(Proof::Secure);
+                    let mut response = response(request, ResponseCode::NoError);
+                    response.add_answer(answer);
+                    response
+                })
+            };
+            let (address, mut server) = serve(builder(forward), vec![("secure", secure)]).await;
+            let with_do = query("example.com.", |message| {
+                let mut edns = Edns::new();
+                edns.set_dnssec_ok(true);
+                message.set_edns(edns);
+            });
+            assert!(ask(address, &with_do).await.authentic_data());
+            let with_ad = query("example.com.", |message| {
+                message.set_authentic_data(true);
+            });
+            assert!(ask(address, &with_ad).await.authentic_data());
+            // a client which doesn't understand the bit
+            let plain = query("example.com.", |_| ());
+            assert!(!ask(address, &plain).await.authentic_data());
+            if forward == ForwardMode::Raw {
+                // nothing was validated for the client which disabled checking
+                let with_cd = query("example.com.", |message| {
+                    message.set_authentic_data(true).set_checking_disabled(true);
+                });
+                assert!(!ask(address, &with_cd).await.authentic_data());
+            }
+            let _ = server.shutdown_gracefully().await;
+        }
+    }
+
+    #[cfg(feature = "dnssec")]
+    #[tokio::test]
+    async fn drop_bogus_negative_answers() {
+        use hickory_proto::dnssec::Proof;
+
+        // the forged answer arrives both before and after the genuine one
+        for forward in [ForwardMode::Raw, ForwardMode::Resolve] {
+            for delay in [0, 100] {
+                let forged = upstream(delay, |request| {
+                    let mut soa = soa_record();
+                    soa.set_proof(Proof::Bogus);
+                    let mut response = response(request, ResponseCode::NXDomain);
+                    response.add_name_server(soa);
+                    response
+                });
+                let nodata = upstream(50, |request| {
+                    let mut response = response(request, ResponseCode::NoError);
+                    response.add_name_server(soa_record());
+                    response
+                });
+                let (address, mut server) = serve(
+                    builder(forward),
+                    vec![("forged", forged), ("nodata", nodata)],
+                )
+                .await;
+                let response = ask(address, &query("example.com.", |_| ())).await;
+                assert_eq!(response.response_code(), ResponseCode::NoError);
+                assert!(response.answers().is_empty());
+                assert_eq!(response.name_servers()[0].record_type(), RecordType::SOA);
+                let _ = server.shutdown_gracefully().await;
+            }
+        }
+    }
+}
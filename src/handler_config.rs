@@ -1,81 +1,176 @@
-use crate::config::{Config, RequestRule, ResponseRule};
+use crate::config::{Config, DomainsConf, ForwardMode, IpRangeConf, RequestRule, ResponseRule};
+use crate::dnstap::Dnstap;
 use crate::domain::DomainSuffix;
 use crate::ip::IpRange;
-use crate::resolver::RecursiveResolver;
+use crate::metrics::Metrics;
+use crate::querylog::QueryLog;
+use crate::resolver::{RecursiveResolver, UpstreamResolver};
+use crate::resolver_proxy::ProxyHostResolver;
 use regex::RegexSet;
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
 
-#[derive(Debug)]
+/// The configuration in use, replaced when it is reloaded
+pub type SharedConfig = Arc<RwLock<Arc<HandlerConfig>>>;
+
+#[derive(Debug, Clone)]
 pub struct HandlerConfig {
     pub defaults: Arc<Vec<String>>,
-    pub resolvers: Arc<HashMap<String, Arc<RecursiveResolver>>>,
+    pub forward: ForwardMode,
+    pub minimal_responses: bool,
+    pub max_udp_payload: u16,
+    pub resolvers: Arc<HashMap<String, Arc<dyn UpstreamResolver>>>,
+    /// The resolvers added in code, kept when the config is reloaded
+    pub custom: Arc<HashMap<String, Arc<dyn UpstreamResolver>>>,
     pub domains: Arc<HashMap<String, Domains>>,
     pub ranges: Arc<HashMap<String, IpRange>>,
     pub request_rules: Arc<Vec<RequestRule>>,
     pub response_rules: Arc<Vec<ResponseRule>>,
+    pub metrics: Arc<Metrics>,
+    pub query_log: Option<Arc<QueryLog>>,
+    pub dnstap: Option<Arc<Dnstap>>,
+    /// The upstreams turned off at runtime
+    pub disabled: Arc<RwLock<HashSet<String>>>,
 }
 
-#[derive(Debug)]
-pub struct Domains {
-    pub regex_set: RegexSet,
-    pub suffix: DomainSuffix,
-}
+impl HandlerConfig {
+    /// The resolver of the upstream `name`, none if it's unknown or turned off
+    pub fn resolver(&self, name: &str) -> Option<Arc<dyn UpstreamResolver>> {
+        if self.disabled.read().unwrap().contains(name) {
+            return None;
+        }
+        self.resolvers.get(name).cloned()
+    }
 
-impl Default for Domains {
-    fn default() -> Self {
-        Self {
-            regex_set: RegexSet::default(),
-            suffix: DomainSuffix::default(),
+    /// Whether the answers of the upstream `name` are validated with DNSSEC
+    #[cfg(feature = "dnssec")]
+    pub fn validates(&self, name: &str) -> bool {
+        self.resolvers
+            .get(name)
+            .is_some_and(|resolver| resolver.validates())
+    }
+
+    /// This configuration with `resolver` as the upstream `name`, replacing the one of
+    /// the config if any. The rules refer to it by `name`.
+    pub fn with_resolver(
+        mut self,
+        name: impl Into<String>,
+        resolver: impl UpstreamResolver + 'static,
+    ) -> Self {
+        let name = name.into();
+        let resolver: Arc<dyn UpstreamResolver> = Arc::new(resolver);
+        Arc::make_mut(&mut self.resolvers).insert(name.clone(), resolver.clone());
+        Arc::make_mut(&mut self.custom).insert(name, resolver);
+        self
+    }
+
+    /// A configuration built from `config`, keeping the state which outlives a reload
+    pub fn reload(&self, config: Config) -> Self {
+        let mut config = HandlerConfig {
+            metrics: self.metrics.clone(),
+            query_log: self.query_log.clone(),
+            dnstap: self.dnstap.clone(),
+            disabled: self.disabled.clone(),
+            ..config.into()
+        };
+        for (name, resolver) in self.custom.iter() {
+            Arc::make_mut(&mut config.resolvers).insert(name.clone(), resolver.clone());
+        }
+        config.custom = self.custom.clone();
+        config
+    }
+
+    /// This configuration with the domain and range lists of `config` read again,
+    /// the upstreams and their caches are kept
+    pub fn reload_lists(&self, config: Config) -> Self {
+        HandlerConfig {
+            domains: Arc::new(build_domains(config.domains)),
+            ranges: Arc::new(build_ranges(config.ranges)),
+            ..self.clone()
         }
     }
 }
 
+#[derive(Debug, Default)]
+pub struct Domains {
+    pub regex_set: RegexSet,
+    pub suffix: DomainSuffix,
+}
+
 impl From<Config> for HandlerConfig {
     fn from(config: Config) -> Self {
         // debug!(STDERR, "{:#?}", config);
+        // the bootstrap upstream resolves its own proxies with the system resolver
+        let bootstrap = config.bootstrap.as_ref().map(|name| {
+            let upstream = &config.upstreams[name];
+            (
+                name,
+                Arc::new(RecursiveResolver::from((
+                    upstream,
+                    Some(config.resolver_opts),
+                ))),
+            )
+        });
+        let proxy_hosts = Arc::new(ProxyHostResolver::new(
+            bootstrap.as_ref().map(|(_, resolver)| resolver.clone()),
+        ));
         let resolvers: HashMap<_, _> = config
             .upstreams
             .iter()
             .map(|(name, upstream)| {
-                (
-                    name.clone(),
-                    Arc::new((upstream, Some(config.resolver_opts)).into()),
-                )
-            })
-            .collect();
-
-        let domains: HashMap<_, _> = config
-            .domains
-            .into_iter()
-            .map(|(name, domains)| match domains.build() {
-                Ok(domains) => (
-                    name,
-                    Domains {
-                        regex_set: RegexSet::new(&domains.regex_set).unwrap_or_default(),
-                        suffix: domains.suffix_set.join("\n").parse().unwrap_or_default(),
-                    },
-                ),
-                Err(_) => (name, Domains::default()),
-            })
-            .collect();
-
-        let ranges: HashMap<_, _> = config
-            .ranges
-            .into_iter()
-            .map(|(key, ip_range)| match ip_range.build() {
-                Ok(ip_range) => (key, ip_range),
-                Err(_) => (key, IpRange::default()),
+                let resolver: Arc<dyn UpstreamResolver> = match &bootstrap {
+                    Some((bootstrap, resolver)) if *bootstrap == name => resolver.clone(),
+                    _ => Arc::new(RecursiveResolver::from_upstream(
+                        upstream,
+                        Some(config.resolver_opts),
+                        proxy_hosts.clone(),
+                    )),
+                };
+                (name.clone(), resolver)
             })
             .collect();
 
         HandlerConfig {
             defaults: Arc::new(config.default_upstreams),
+            forward: config.forward,
+            minimal_responses: config.minimal_responses,
+            max_udp_payload: config.max_udp_payload,
             resolvers: Arc::new(resolvers),
-            domains: Arc::new(domains),
-            ranges: Arc::new(ranges),
+            custom: Arc::default(),
+            domains: Arc::new(build_domains(config.domains)),
+            ranges: Arc::new(build_ranges(config.ranges)),
             request_rules: Arc::new(config.request_rules),
             response_rules: Arc::new(config.response_rules),
+            metrics: Arc::default(),
+            query_log: None,
+            dnstap: None,
+            disabled: Arc::default(),
         }
     }
 }
+
+fn build_domains(domains: HashMap<String, DomainsConf>) -> HashMap<String, Domains> {
+    domains
+        .into_iter()
+        .map(|(name, domains)| match domains.build() {
+            Ok(domains) => (
+                name,
+                Domains {
+                    regex_set: RegexSet::new(&domains.regex_set).unwrap_or_default(),
+                    suffix: domains.suffix_set.join("\n").parse().unwrap_or_default(),
+                },
+            ),
+            Err(_) => (name, Domains::default()),
+        })
+        .collect()
+}
+
+fn build_ranges(ranges: HashMap<String, IpRangeConf>) -> HashMap<String, IpRange> {
+    ranges
+        .into_iter()
+        .map(|(key, ip_range)| match ip_range.build(&key) {
+            Ok(ip_range) => (key, ip_range),
+            Err(_) => (key, IpRange::default()),
+        })
+        .collect()
+}
@@ -0,0 +1,150 @@
+use log::{debug, warn};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A request to the HTTP listeners, only its head is read
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub headers: HashMap<String, String>,
+}
+
+impl Request {
+    fn parse(head: &str) -> Option<Self> {
+        let mut lines = head.split("\r\n");
+        let mut parts = lines.next()?.split_whitespace();
+        let method = parts.next()?.to_string();
+        let target = parts.next()?;
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+            .collect();
+        Some(Request {
+            method,
+            path: path.to_string(),
+            query: url::form_urlencoded::parse(query.as_bytes())
+                .into_owned()
+                .collect(),
+            headers,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn text(status: &'static str, body: String) -> Self {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body,
+        }
+    }
+
+    pub fn json<T: Serialize>(status: &'static str, body: &T) -> Self {
+        match serde_json::to_string_pretty(body) {
+            Ok(body) => Response {
+                status,
+                content_type: "application/json",
+                body,
+            },
+            Err(e) => Response::text("500 Internal Server Error", e.to_string()),
+        }
+    }
+
+    pub fn not_found() -> Self {
+        Response::text("404 Not Found", String::new())
+    }
+}
+
+/// Answer the requests of `listener` with `handle`, one request per connection
+pub async fn serve<F, Fut>(listener: TcpListener, handle: F)
+where
+    F: Fn(Request) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Response> + Send,
+{
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("Failed to accept an HTTP connection: {}", e);
+                continue;
+            }
+        };
+        let handle = handle.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, handle).await {
+                debug!("HTTP request from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn respond<F, Fut>(mut stream: TcpStream, handle: F) -> std::io::Result<()>
+where
+    F: Fn(Request) -> Fut,
+    Fut: Future<Output = Response>,
+{
+    // the body of the request is ignored
+    let mut buf = vec![0; 8192];
+    let mut len = 0;
+    let head_len = loop {
+        if let Some(end) = buf[..len].windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        if len == buf.len() {
+            break len;
+        }
+        let read = tokio::time::timeout(Duration::from_secs(10), stream.read(&mut buf[len..]))
+            .await
+            .map_err(|_| std::io::ErrorKind::TimedOut)??;
+        if read == 0 {
+            break len;
+        }
+        len += read;
+    };
+    let head = String::from_utf8_lossy(&buf[..head_len]);
+    let response = match Request::parse(&head) {
+        Some(request) => handle(request).await,
+        None => Response::text("400 Bad Request", String::new()),
+    };
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_request() {
+        let request = Request::parse(
+            "POST /cache/flush?name=example.com.&all HTTP/1.1\r\nAuthorization: Bearer x y",
+        )
+        .unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/cache/flush");
+        assert_eq!(request.query["name"], "example.com.");
+        assert_eq!(request.query["all"], "");
+        assert_eq!(request.headers["authorization"], "Bearer x y");
+        assert!(Request::parse("").is_none());
+    }
+}
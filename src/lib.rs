@@ -0,0 +1,60 @@
+//! The rule engine and the resolvers of ya-dns, to run it or embed it.
+//!
+//! A [`Config`] is read from a file with [`ConfigBuilder::from_file`] or built in
+//! code with [`ConfigBuilder::new`]. [`server::run`] serves it like the `yadns`
+//! binary does, or a [`Handler`] built from it answers the requests of a hickory
+//! server of your own. Upstreams of your own implement [`UpstreamResolver`] and are
+//! added with [`HandlerConfig::with_resolver`], [`server::run_with`] serves them.
+
+pub use config::{
+    Config, ConfigBuilder, ConfigError, DomainsConf, ForwardMode, IpRangeConf, NetworkType,
+    RequestRuleConfig, ResponseRule, RuleAction, UpstreamConfig,
+};
+pub use ecs::QueryClient;
+pub use handler::Handler;
+pub use handler_config::HandlerConfig;
+pub use metrics::CacheStats;
+pub use resolver::{RecursiveResolver, UpstreamResolver};
+pub use resolver_proxy::{ProxyConfig, ProxyMode};
+pub use sections::Sections;
+
+mod admin;
+pub mod bench;
+pub mod check;
+pub mod config;
+#[cfg(feature = "dnssec")]
+mod dnssec;
+mod dnstap;
+mod domain;
+mod ecs;
+mod edns;
+pub mod explain;
+mod filter;
+pub mod handler;
+pub mod handler_config;
+mod http_server;
+mod ip;
+mod metrics;
+pub mod query;
+mod querylog;
+pub mod resolver;
+#[cfg(feature = "dnscrypt")]
+mod resolver_dnscrypt;
+#[cfg(any(feature = "dns-over-https", feature = "dns-over-h3"))]
+mod resolver_doh;
+mod resolver_forward;
+pub mod resolver_proxy;
+mod resolver_runtime_provider;
+#[cfg(feature = "shadowsocks")]
+mod resolver_shadowsocks;
+mod resolver_system;
+#[cfg(any(
+    feature = "dns-over-tls",
+    feature = "dns-over-https",
+    feature = "dns-over-h3",
+    feature = "dns-over-quic"
+))]
+mod resolver_tls;
+mod sections;
+pub mod server;
+mod stamp;
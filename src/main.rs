@@ -1,26 +1,16 @@
-use crate::config::{Config, ConfigBuilder, ConfigError};
-use crate::handler::Handler;
-use crate::option::Args;
+use crate::option::{Args, Command};
 use clap::Parser;
-use hickory_server::ServerFuture;
-use log::info;
+use hickory_proto::rr::Name;
 use std::io;
 use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::str::FromStr;
+#[cfg(feature = "debug")]
 use std::time::Duration;
-use tokio;
-use tokio::net::{TcpListener, UdpSocket};
+use yadns::config::{Config, ConfigBuilder, ConfigError};
+use yadns::{HandlerConfig, bench, check, explain, query, server};
 
-mod config;
-mod domain;
-mod filter;
-mod handler;
-mod handler_config;
-mod ip;
 mod option;
-mod resolver;
-mod resolver_proxy;
-mod resolver_runtime_provider;
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
@@ -30,29 +20,94 @@ async fn main() -> io::Result<()> {
         .server_addr(([0, 0, 0, 0], 5555))
         .init();
 
-    let config = match config() {
-        Ok(cfg) => cfg,
-        Err(e) => {
-            eprintln!("Error loading configuration: {}", e);
-            exit(1);
+    let args = Args::parse();
+    // a server is benchmarked without any config
+    if let Some(Command::Bench(bench)) = args.command {
+        let config = match bench.server {
+            Some(_) => None,
+            None => Some(load_config(args.config).0),
+        };
+        let queries = std::fs::read_to_string(&bench.names)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| bench::read_queries(&contents, bench.record_type));
+        let queries = match queries {
+            Ok(queries) => queries,
+            Err(e) => {
+                eprintln!("{}: {}", bench.names.display(), e);
+                exit(1);
+            }
+        };
+        match bench::run(queries, bench.options(), config).await {
+            Ok(report) => print!("{}", report),
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            }
         }
-    };
+        return Ok(());
+    }
+    let (config, config_path) = load_config(args.config);
+
+    match args.command {
+        Some(Command::Check) => {
+            println!("{}: ok", config_path.display());
+            return Ok(());
+        }
+        Some(Command::Explain {
+            name,
+            record_type,
+            answer,
+        }) => {
+            let name = parse_name(&name);
+            let config = HandlerConfig::from(config);
+            print!("{}", explain::explain(&config, &name, record_type, &answer));
+            return Ok(());
+        }
+        Some(Command::Query {
+            upstream,
+            name,
+            record_type,
+            dnssec,
+        }) => {
+            let Some(transport) = config.upstreams.get(&upstream).map(query::transport) else {
+                let mut names: Vec<_> = config.upstreams.keys().collect();
+                names.sort();
+                eprintln!("Unknown upstream {}, it is one of {:?}", upstream, names);
+                exit(1);
+            };
+            let name = parse_name(&name);
+            let config = HandlerConfig::from(config);
+            let resolver = &*config.resolvers[&upstream];
+            match query::query(resolver, &upstream, &transport, name, record_type, dnssec).await {
+                Ok(out) => print!("{}", out),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            }
+            return Ok(());
+        }
+        Some(Command::Bench(_)) | None => (),
+    }
 
     #[cfg(feature = "logging")]
     init_logger(config.log_level);
 
-    let bind_socket = config.bind;
-    let mut server = ServerFuture::new(Handler::new(config.into()));
-
-    let bind = UdpSocket::bind(bind_socket).await?;
-    info!("Listening on UDP: {}", bind_socket);
-    server.register_socket(bind);
-
-    let bind = TcpListener::bind(bind_socket).await?;
-    info!("Listening on TCP: {}", bind_socket);
-    server.register_listener(bind, Duration::from_secs(10));
+    if let Err(e) = server::run(config, Some(config_path)).await {
+        eprintln!("{}", e);
+        exit(1);
+    }
+    Ok(())
+}
 
-    Ok(server.block_until_done().await?)
+fn parse_name(name: &str) -> Name {
+    match Name::from_str(name) {
+        Ok(name) => name,
+        Err(e) => {
+            eprintln!("Invalid name {}: {}", name, e);
+            exit(1);
+        }
+    }
 }
 
 #[cfg(feature = "logging")]
@@ -63,9 +118,28 @@ fn init_logger(log_level: log::LevelFilter) {
     builder.init();
 }
 
-fn config() -> Result<Config, ConfigError> {
-    let args = Args::parse();
-    let config_path = match args.config {
+/// The config at `path`, exiting on errors
+fn load_config(path: Option<String>) -> (Config, PathBuf) {
+    let (config, config_path) = match config(path) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            eprintln!("Error loading configuration: {}", e);
+            exit(1);
+        }
+    };
+
+    let errors = check::check(&config);
+    for e in &errors {
+        eprintln!("Error in {}: {}", config_path.display(), e);
+    }
+    if !errors.is_empty() {
+        exit(1);
+    }
+    (config, config_path)
+}
+
+fn config(path: Option<String>) -> Result<(Config, PathBuf), ConfigError> {
+    let config_path = match path {
         Some(path) => PathBuf::from(path),
         None => {
             let default_files = ["config.toml", "config.yaml", "config.yml"];
@@ -82,5 +156,5 @@ fn config() -> Result<Config, ConfigError> {
     };
 
     let builder = ConfigBuilder::from_file(&config_path)?;
-    builder.build()
+    Ok((builder.build()?, config_path))
 }
@@ -0,0 +1,404 @@
+use crate::handler_config::SharedConfig;
+use crate::http_server::{self, Request, Response};
+use crate::resolver::UpstreamResolver;
+use hickory_proto::op::ResponseCode;
+use hickory_proto::{ProtoError, ProtoErrorKind};
+use hickory_resolver::ResolveError;
+use serde_derive::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpListener;
+
+/// Upper bounds in seconds of the latency buckets
+const LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// The counters of the handler, labeled by query type, upstream or rule
+#[derive(Debug, Default)]
+pub struct Metrics {
+    queries: Counter,
+    upstream_requests: Counter,
+    upstream_errors: Counter,
+    upstream_timeouts: Counter,
+    upstream_wins: Counter,
+    rule_drops: Counter,
+    dnstap_drops: Counter,
+    query_log_drops: Counter,
+    upstream_latency: Mutex<BTreeMap<String, Histogram>>,
+    /// The last outcome of each upstream and when it was
+    last_outcomes: Mutex<HashMap<String, (Outcome, SystemTime)>>,
+}
+
+/// How an upstream answered a request
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    /// A response, including a negative one
+    Response,
+    Error,
+    Timeout,
+}
+
+impl From<&ProtoError> for Outcome {
+    fn from(error: &ProtoError) -> Self {
+        match error.kind() {
+            ProtoErrorKind::NoRecordsFound { .. } => Outcome::Response,
+            ProtoErrorKind::Timeout => Outcome::Timeout,
+            _ => Outcome::Error,
+        }
+    }
+}
+
+impl From<&ResolveError> for Outcome {
+    fn from(error: &ResolveError) -> Self {
+        error.proto().map_or(Outcome::Error, Outcome::from)
+    }
+}
+
+impl Metrics {
+    pub fn query(&self, query_type: Option<String>, code: ResponseCode) {
+        let query_type = query_type.unwrap_or_else(|| "none".to_string());
+        let rcode = format!("{:?}", code).to_uppercase();
+        self.queries
+            .inc(labels(&[("type", &query_type), ("rcode", &rcode)]));
+    }
+
+    pub fn upstream_request(&self, upstream: &str) {
+        self.upstream_requests
+            .inc(labels(&[("upstream", upstream)]));
+    }
+
+    /// Record how `upstream` answered after `elapsed`, or `None` when it took too long
+    pub fn upstream_response(&self, upstream: &str, elapsed: Duration, outcome: Option<Outcome>) {
+        let label = labels(&[("upstream", upstream)]);
+        let outcome = outcome.unwrap_or(Outcome::Timeout);
+        self.last_outcomes
+            .lock()
+            .unwrap()
+            .insert(upstream.to_string(), (outcome, SystemTime::now()));
+        match outcome {
+            Outcome::Response => self
+                .upstream_latency
+                .lock()
+                .unwrap()
+                .entry(label)
+                .or_default()
+                .observe(elapsed.as_secs_f64()),
+            Outcome::Error => self.upstream_errors.inc(label),
+            Outcome::Timeout => self.upstream_timeouts.inc(label),
+        }
+    }
+
+    /// `upstream` answered first with a response the rules accepted
+    pub fn upstream_won(&self, upstream: &str) {
+        self.upstream_wins.inc(labels(&[("upstream", upstream)]));
+    }
+
+    /// The response rule at `index` dropped a response
+    pub fn rule_dropped(&self, index: usize) {
+        self.rule_drops.inc(labels(&[("rule", &index.to_string())]));
+    }
+
+    /// The counters of `upstream`, and how it last answered
+    pub fn upstream_stats(&self, upstream: &str) -> UpstreamStats {
+        let label = labels(&[("upstream", upstream)]);
+        let last = self.last_outcomes.lock().unwrap().get(upstream).copied();
+        UpstreamStats {
+            requests: self.upstream_requests.get(&label),
+            errors: self.upstream_errors.get(&label),
+            timeouts: self.upstream_timeouts.get(&label),
+            wins: self.upstream_wins.get(&label),
+            last_outcome: last.map(|(outcome, _)| outcome),
+            last_outcome_at: last
+                .map(|(_, time)| humantime::format_rfc3339_seconds(time).to_string()),
+        }
+    }
+
+    /// A dnstap frame was dropped for `reason`
+    pub fn dnstap_dropped(&self, reason: &str) {
+        self.dnstap_drops.inc(labels(&[("reason", reason)]));
+    }
+
+    /// A query log line was dropped for `reason`
+    pub fn query_log_dropped(&self, reason: &str) {
+        self.query_log_drops.inc(labels(&[("reason", reason)]));
+    }
+
+    /// The metrics in the Prometheus text format, with the cache counters of `resolvers`
+    pub fn render(&self, resolvers: &HashMap<String, Arc<dyn UpstreamResolver>>) -> String {
+        let mut out = String::new();
+        self.queries.render(
+            &mut out,
+            "yadns_queries_total",
+            "Queries answered, by type and response code",
+        );
+        self.upstream_requests.render(
+            &mut out,
+            "yadns_upstream_requests_total",
+            "Requests sent to each upstream",
+        );
+        self.upstream_errors.render(
+            &mut out,
+            "yadns_upstream_errors_total",
+            "Requests to each upstream which failed",
+        );
+        self.upstream_timeouts.render(
+            &mut out,
+            "yadns_upstream_timeouts_total",
+            "Requests to each upstream which timed out",
+        );
+        self.upstream_wins.render(
+            &mut out,
+            "yadns_upstream_wins_total",
+            "Queries answered with the response of each upstream",
+        );
+        self.rule_drops.render(
+            &mut out,
+            "yadns_response_rule_drops_total",
+            "Responses dropped by each response rule, by its index",
+        );
+        self.dnstap_drops.render(
+            &mut out,
+            "yadns_dnstap_dropped_total",
+            "Dnstap frames dropped, by reason",
+        );
+        self.query_log_drops.render(
+            &mut out,
+            "yadns_query_log_dropped_total",
+            "Query log lines dropped, by reason",
+        );
+
+        let name = "yadns_upstream_latency_seconds";
+        header(
+            &mut out,
+            name,
+            "histogram",
+            "Latency of the responses of each upstream",
+        );
+        for (label, histogram) in self.upstream_latency.lock().unwrap().iter() {
+            histogram.render(&mut out, name, label);
+        }
+
+        let mut resolvers: Vec<_> = resolvers.iter().collect();
+        resolvers.sort_by_key(|(name, _)| *name);
+        for (name, help, hits) in [
+            (
+                "yadns_cache_hits_total",
+                "Lookups answered from the cache",
+                true,
+            ),
+            (
+                "yadns_cache_misses_total",
+                "Lookups sent to the upstream",
+                false,
+            ),
+        ] {
+            header(&mut out, name, "counter", help);
+            for (upstream, resolver) in &resolvers {
+                let Some(stats) = resolver.cache_stats() else {
+                    continue;
+                };
+                let value = if hits { stats.hits() } else { stats.misses() };
+                let label = labels(&[("upstream", upstream)]);
+                let _ = writeln!(out, "{}{{{}}} {}", name, label, value);
+            }
+        }
+        out
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct UpstreamStats {
+    pub requests: u64,
+    pub errors: u64,
+    pub timeouts: u64,
+    pub wins: u64,
+    pub last_outcome: Option<Outcome>,
+    pub last_outcome_at: Option<String>,
+}
+
+/// Hits and misses of the cache of an upstream
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CacheStats {
+    pub fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+/// Counts by label set
+#[derive(Debug, Default)]
+struct Counter(Mutex<BTreeMap<String, u64>>);
+
+impl Counter {
+    fn inc(&self, labels: String) {
+        *self.0.lock().unwrap().entry(labels).or_default() += 1;
+    }
+
+    fn get(&self, labels: &str) -> u64 {
+        self.0.lock().unwrap().get(labels).copied().unwrap_or(0)
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, "counter", help);
+        for (labels, value) in self.0.lock().unwrap().iter() {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        for (count, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, bound, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{},le=\"+Inf\"}} {}",
+            name, labels, self.count
+        );
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn labels(pairs: &[(&str, &str)]) -> String {
+    pairs
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Serve the metrics at `/metrics` over plain HTTP
+pub async fn serve(listener: TcpListener, config: SharedConfig) {
+    http_server::serve(listener, move |request: Request| {
+        let config = config.read().unwrap().clone();
+        async move {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/metrics") => Response {
+                    status: "200 OK",
+                    content_type: "text/plain; version=0.0.4",
+                    body: config.metrics.render(&config.resolvers),
+                },
+                _ => Response::not_found(),
+            }
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConfigBuilder;
+    use crate::handler_config::HandlerConfig;
+    use std::sync::RwLock;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn render_metrics() {
+        let metrics = Metrics::default();
+        metrics.query(Some("A".to_string()), ResponseCode::NXDomain);
+        metrics.query(Some("A".to_string()), ResponseCode::NXDomain);
+        metrics.upstream_request("quad\"9");
+        metrics.upstream_response("google", Duration::from_millis(30), Some(Outcome::Response));
+        metrics.upstream_response("google", Duration::from_secs(5), None);
+        metrics.rule_dropped(2);
+        let text = metrics.render(&HashMap::new());
+        assert!(text.contains("yadns_queries_total{type=\"A\",rcode=\"NXDOMAIN\"} 2"));
+        assert!(text.contains("yadns_upstream_requests_total{upstream=\"quad\\\"9\"} 1"));
+        assert!(text.contains("yadns_upstream_timeouts_total{upstream=\"google\"} 1"));
+        assert!(text.contains("yadns_response_rule_drops_total{rule=\"2\"} 1"));
+        let name = "yadns_upstream_latency_seconds";
+        assert!(text.contains(&format!(
+            "{name}_bucket{{upstream=\"google\",le=\"0.025\"}} 0"
+        )));
+        assert!(text.contains(&format!(
+            "{name}_bucket{{upstream=\"google\",le=\"0.05\"}} 1"
+        )));
+        assert!(text.contains(&format!("{name}_count{{upstream=\"google\"}} 1")));
+    }
+
+    #[tokio::test]
+    async fn serve_metrics() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = HandlerConfig::from(
+            toml::from_str::<ConfigBuilder>(
+                r#"
+                bind = "127.0.0.1:5300"
+                [upstreams.google]
+                address = ["192.0.2.53"]
+                network = "udp"
+                "#,
+            )
+            .unwrap()
+            .build()
+            .unwrap(),
+        );
+        config.metrics.upstream_won("google");
+        tokio::spawn(serve(listener, Arc::new(RwLock::new(Arc::new(config)))));
+
+        for (path, status) in [("/metrics", "200 OK"), ("/", "404 Not Found")] {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with(&format!("HTTP/1.1 {}", status)));
+            assert_eq!(
+                response.contains("yadns_upstream_wins_total{upstream=\"google\"} 1"),
+                path == "/metrics"
+            );
+        }
+    }
+}
@@ -1,4 +1,9 @@
-use clap::Parser;
+use clap::{Args as ClapArgs, Parser, Subcommand, ValueEnum};
+use hickory_proto::rr::RecordType;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+use yadns::bench::{BenchOptions, Protocol};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -7,8 +12,118 @@ pub struct Args {
     #[arg(
         short,
         long,
+        global = true,
         value_name = "CONFIG_FILE",
         help = "Specify the config file. If not provided, it will search for config.toml, config.yaml, and config.yml in the current directory."
     )]
     pub config: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Validate the config file and exit
+    Check,
+    /// Show how the rules route a query, without sending it
+    Explain {
+        /// The name to query
+        name: String,
+        /// The record type to query
+        #[arg(default_value = "A", value_parser = parse_record_type)]
+        record_type: RecordType,
+        /// A sample answer to check against the response rules of each upstream
+        #[arg(short, long, value_name = "IP")]
+        answer: Vec<IpAddr>,
+    },
+    /// Ask an upstream a question, the way dig does
+    Query {
+        /// The upstream to ask
+        upstream: String,
+        /// The name to query
+        name: String,
+        /// The record type to query
+        #[arg(default_value = "A", value_parser = parse_record_type)]
+        record_type: RecordType,
+        /// Set the DNSSEC OK bit
+        #[arg(long)]
+        dnssec: bool,
+    },
+    /// Measure the throughput and the latency of a server
+    Bench(BenchArgs),
+}
+
+#[derive(ClapArgs, Debug)]
+pub struct BenchArgs {
+    /// A file of names, each optionally followed by a record type, or a query log
+    pub names: PathBuf,
+    /// The record type of the names without one
+    #[arg(short = 't', long = "type", default_value = "A", value_parser = parse_record_type)]
+    pub record_type: RecordType,
+    /// The server to load. Without it the handler of the config is run in process.
+    #[arg(short, long)]
+    pub server: Option<SocketAddr>,
+    #[arg(short, long, value_enum, default_value = "udp")]
+    pub protocol: BenchProtocol,
+    /// The name in the certificate of the server, for tls and https
+    #[arg(long)]
+    pub tls_host: Option<String>,
+    /// The number of queries in flight
+    #[arg(long, default_value_t = 10)]
+    pub concurrency: usize,
+    /// The queries sent per second, as fast as the server answers when not set
+    #[arg(short, long)]
+    pub qps: Option<u32>,
+    /// The number of queries sent, by default each name once
+    #[arg(short = 'n', long)]
+    pub count: Option<usize>,
+    /// Seconds before a query times out
+    #[arg(long, default_value_t = 5)]
+    pub timeout: u64,
+}
+
+impl BenchArgs {
+    pub fn options(&self) -> BenchOptions {
+        BenchOptions {
+            server: self.server,
+            protocol: match self.protocol {
+                BenchProtocol::Udp => Protocol::Udp,
+                BenchProtocol::Tcp => Protocol::Tcp,
+                #[cfg(feature = "dns-over-tls")]
+                BenchProtocol::Tls => Protocol::Tls,
+                #[cfg(feature = "dns-over-https")]
+                BenchProtocol::Https => Protocol::Https,
+            },
+            tls_host: self.tls_host.clone(),
+            concurrency: self.concurrency,
+            qps: self.qps,
+            count: self.count,
+            timeout: Duration::from_secs(self.timeout),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum BenchProtocol {
+    Udp,
+    Tcp,
+    #[cfg(feature = "dns-over-tls")]
+    Tls,
+    #[cfg(feature = "dns-over-https")]
+    Https,
+}
+
+fn parse_record_type(s: &str) -> Result<RecordType, String> {
+    s.to_ascii_uppercase().parse().map_err(|e| format!("{}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn arguments() {
+        Args::command().debug_assert();
+    }
 }
@@ -0,0 +1,267 @@
+use crate::config::Upstream;
+use crate::resolver::UpstreamResolver;
+use crate::resolver_proxy::{Proxy, ProxyConfig};
+use hickory_proto::op::{Edns, Message, MessageType, OpCode, Query};
+use hickory_proto::rr::{Name, RecordType};
+use std::fmt::Write;
+use std::time::{Instant, SystemTime};
+
+/// Send one question to an upstream, bypassing the caches, and describe the response
+/// the way dig does
+pub async fn query(
+    resolver: &dyn UpstreamResolver,
+    upstream: &str,
+    transport: &str,
+    name: Name,
+    record_type: RecordType,
+    dnssec: bool,
+) -> Result<String, String> {
+    let mut message = Message::new();
+    let mut edns = Edns::new();
+    edns.set_max_payload(1232).set_dnssec_ok(dnssec);
+    message
+        .set_id(rand::random())
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true)
+        .add_query(Query::query(name.clone(), record_type))
+        .set_edns(edns);
+
+    let started = Instant::now();
+    let response = resolver.forward(&message, None).await.map_err(|e| {
+        format!(
+            "{} {} through {} ({}) failed: {}",
+            name, record_type, upstream, transport, e
+        )
+    })?;
+    let elapsed = started.elapsed();
+
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "; <<>> yadns <<>> @{} {} {}",
+        upstream, name, record_type
+    );
+    let _ = write!(out, "{}", describe(&response));
+    let _ = writeln!(out, ";; Query time: {} msec", elapsed.as_millis());
+    let _ = writeln!(out, ";; SERVER: {} ({})", upstream, transport);
+    let _ = writeln!(
+        out,
+        ";; WHEN: {}",
+        humantime::format_rfc3339_seconds(SystemTime::now())
+    );
+    let size = response.to_vec().map_or(0, |buffer| buffer.len());
+    let _ = writeln!(out, ";; MSG SIZE  rcvd: {}", size);
+    Ok(out)
+}
+
+/// The header and the sections of `response`
+fn describe(response: &Message) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        ";; ->>HEADER<<- opcode: {}, status: {}, id: {}",
+        format!("{:?}", response.op_code()).to_uppercase(),
+        format!("{:?}", response.response_code()).to_uppercase(),
+        response.id()
+    );
+    let flags: Vec<_> = [
+        (response.message_type() == MessageType::Response, "qr"),
+        (response.authoritative(), "aa"),
+        (response.truncated(), "tc"),
+        (response.recursion_desired(), "rd"),
+        (response.recursion_available(), "ra"),
+        (response.authentic_data(), "ad"),
+        (response.checking_disabled(), "cd"),
+    ]
+    .into_iter()
+    .filter_map(|(set, flag)| set.then_some(flag))
+    .collect();
+    let _ = writeln!(
+        out,
+        ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+        flags.join(" "),
+        response.query_count(),
+        response.answer_count(),
+        response.name_server_count(),
+        response.additional_count()
+    );
+    if let Some(edns) = response.extensions() {
+        let _ = writeln!(out, "\n;; OPT PSEUDOSECTION:");
+        let _ = writeln!(
+            out,
+            "; EDNS: version: {}, flags:{}; udp: {}",
+            edns.version(),
+            if edns.flags().dnssec_ok { " do" } else { "" },
+            edns.max_payload()
+        );
+    }
+    let _ = writeln!(out, "\n;; QUESTION SECTION:");
+    for query in response.queries() {
+        let _ = writeln!(
+            out,
+            ";{}\t\t{}\t{}",
+            query.name(),
+            query.query_class(),
+            query.query_type()
+        );
+    }
+    let sections = [
+        ("ANSWER", response.answers()),
+        ("AUTHORITY", response.name_servers()),
+        ("ADDITIONAL", response.additionals()),
+    ];
+    for (section, records) in sections {
+        if records.is_empty() {
+            continue;
+        }
+        let _ = writeln!(out, "\n;; {} SECTION:", section);
+        for record in records {
+            let _ = writeln!(
+                out,
+                "{}\t{}\t{}\t{}\t{}",
+                record.name(),
+                record.ttl(),
+                record.dns_class(),
+                record.record_type(),
+                record.data()
+            );
+        }
+    }
+    let _ = writeln!(out);
+    out
+}
+
+/// The protocol, name servers and proxies of `upstream`
+pub fn transport(upstream: &Upstream) -> String {
+    let (protocol, address, tls_host, proxy): (_, _, Option<&String>, _) = match upstream {
+        Upstream::UdpUpstream { address, proxy, .. } => ("udp", address, None, proxy),
+        Upstream::TcpUpstream { address, proxy, .. } => ("tcp", address, None, proxy),
+        #[cfg(feature = "dns-over-tls")]
+        Upstream::TlsUpstream {
+            address,
+            tls_host,
+            proxy,
+            ..
+        } => ("tls", address, Some(tls_host), proxy),
+        #[cfg(feature = "dns-over-https")]
+        Upstream::HttpsUpstream {
+            address,
+            tls_host,
+            proxy,
+            ..
+        } => ("https", address, Some(tls_host), proxy),
+        #[cfg(feature = "dns-over-h3")]
+        Upstream::H3Upstream {
+            address,
+            tls_host,
+            proxy,
+            ..
+        } => ("h3", address, Some(tls_host), proxy),
+        #[cfg(feature = "dns-over-quic")]
+        Upstream::QuicUpstream {
+            address,
+            tls_host,
+            proxy,
+            ..
+        } => ("quic", address, Some(tls_host), proxy),
+        #[cfg(feature = "dnscrypt")]
+        Upstream::DnsCryptUpstream {
+            address,
+            provider_name,
+            proxy,
+            ..
+        } => ("dnscrypt", address, Some(provider_name), proxy),
+        Upstream::SystemUpstream { path, proxy, .. } => {
+            let mut out = format!("system, {}", path.display());
+            if let Some(proxy) = proxy {
+                let _ = write!(out, ", {}", describe_proxy(proxy));
+            }
+            return out;
+        }
+    };
+    let address: Vec<_> = address.iter().map(ToString::to_string).collect();
+    let mut out = format!("{} {}", protocol, address.join(" "));
+    if let Some(tls_host) = tls_host {
+        let _ = write!(out, ", host {}", tls_host);
+    }
+    if let Some(proxy) = proxy {
+        let _ = write!(out, ", {}", describe_proxy(proxy));
+    }
+    out
+}
+
+/// The proxies without their credentials
+fn describe_proxy(proxy: &Proxy) -> String {
+    let servers: Vec<_> = proxy
+        .servers
+        .iter()
+        .map(|server| {
+            ProxyConfig {
+                username: None,
+                password: None,
+                ..server.clone()
+            }
+            .to_string()
+        })
+        .collect();
+    let mode = format!("{:?}", proxy.mode).to_lowercase();
+    format!("{} proxy {}", mode, servers.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolver::RecursiveResolver;
+    use hickory_proto::rr::rdata::A;
+    use hickory_proto::rr::{RData, Record};
+    use std::str::FromStr;
+    use tokio::net::UdpSocket;
+
+    #[tokio::test]
+    async fn query_upstream() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+            let request = Message::from_vec(&buf[..len]).unwrap();
+            let mut response = Message::new();
+            response
+                .set_id(request.id())
+                .set_message_type(MessageType::Response)
+                .set_recursion_desired(true)
+                .set_recursion_available(true)
+                .add_queries(request.queries().to_vec())
+                .add_answer(Record::from_rdata(
+                    request.queries()[0].name().clone(),
+                    60,
+                    RData::A(A::new(192, 0, 2, 1)),
+                ));
+            let response = response.to_vec().unwrap();
+            socket.send_to(&response, peer).await.unwrap();
+        });
+        let upstream = Upstream::UdpUpstream {
+            address: vec![addr],
+            proxy: None,
+            bind: Default::default(),
+            ecs: Default::default(),
+            dnssec: None,
+        };
+        let resolver = RecursiveResolver::from((&upstream, None));
+        let out = query(
+            &resolver,
+            "local",
+            &transport(&upstream),
+            Name::from_str("example.com.").unwrap(),
+            RecordType::A,
+            false,
+        )
+        .await
+        .unwrap();
+        assert!(out.contains("status: NOERROR"));
+        assert!(out.contains(";; flags: qr rd ra; QUERY: 1, ANSWER: 1"));
+        assert!(out.contains("example.com.\t60\tIN\tA\t192.0.2.1"));
+        assert!(out.contains(&format!(";; SERVER: local (udp {})", addr)));
+    }
+}
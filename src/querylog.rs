@@ -0,0 +1,335 @@
+use crate::config::{QueryLogConfig, QueryLogFormat};
+use crate::metrics::Metrics;
+use hickory_proto::op::Message;
+use hickory_server::server::Request;
+use log::warn;
+use serde_derive::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::time::{Duration, Instant, SystemTime};
+
+const CSV_HEADER: &str =
+    "timestamp,client,listener,qname,qtype,rule,upstreams,winner,dropped,rcode,answers,latency_ms";
+
+/// How a query was sent to the upstreams
+#[derive(Debug, Default, Serialize)]
+pub struct Route {
+    /// The index of the request rule which matched, none for the default upstreams
+    pub rule: Option<usize>,
+    pub upstreams: Vec<String>,
+    /// The upstream whose response was sent
+    pub winner: Option<String>,
+    pub dropped: Vec<Dropped>,
+}
+
+/// A response which was dropped, by the response rule at `rule` if any
+#[derive(Debug, Serialize)]
+pub struct Dropped {
+    pub upstream: String,
+    pub rule: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct Entry {
+    timestamp: String,
+    client: SocketAddr,
+    listener: String,
+    qname: String,
+    qtype: String,
+    #[serde(flatten)]
+    route: Route,
+    rcode: String,
+    answers: Vec<String>,
+    latency_ms: f64,
+}
+
+impl Entry {
+    fn csv(&self) -> String {
+        let dropped: Vec<_> = self
+            .route
+            .dropped
+            .iter()
+            .map(|dropped| match dropped.rule {
+                Some(rule) => format!("{}:{}", dropped.upstream, rule),
+                None => dropped.upstream.clone(),
+            })
+            .collect();
+        [
+            self.timestamp.clone(),
+            self.client.to_string(),
+            self.listener.clone(),
+            self.qname.clone(),
+            self.qtype.clone(),
+            self.route
+                .rule
+                .map(|rule| rule.to_string())
+                .unwrap_or_default(),
+            self.route.upstreams.join(";"),
+            self.route.winner.clone().unwrap_or_default(),
+            dropped.join(";"),
+            self.rcode.clone(),
+            self.answers.join(";"),
+            self.latency_ms.to_string(),
+        ]
+        .iter()
+        .map(|field| csv_field(field))
+        .collect::<Vec<_>>()
+        .join(",")
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Logs the queries to a file from a thread of its own. Lines are dropped, and
+/// counted, when the buffer is full.
+#[derive(Debug)]
+pub struct QueryLog {
+    format: QueryLogFormat,
+    lines: SyncSender<String>,
+    metrics: Arc<Metrics>,
+}
+
+impl QueryLog {
+    pub fn open(config: QueryLogConfig, metrics: Arc<Metrics>) -> io::Result<Self> {
+        let format = config.format;
+        let (lines, receiver) = mpsc::sync_channel(config.buffer.unwrap_or(10000).max(1));
+        let mut writer = Writer::open(config)?;
+        std::thread::Builder::new()
+            .name("query-log".to_string())
+            .spawn(move || writer.run(receiver))?;
+        Ok(QueryLog {
+            format,
+            lines,
+            metrics,
+        })
+    }
+
+    /// Log `request`, answered with `response` after `latency`
+    pub fn log(&self, request: &Request, route: Route, response: &Message, latency: Duration) {
+        let query = request.queries().first();
+        let entry = Entry {
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            client: request.src(),
+            listener: request.protocol().to_string(),
+            qname: query.map(|q| q.name().to_string()).unwrap_or_default(),
+            qtype: query
+                .map(|q| q.query_type().to_string())
+                .unwrap_or_default(),
+            route,
+            rcode: format!("{:?}", response.response_code()).to_uppercase(),
+            answers: response.answers().iter().map(ToString::to_string).collect(),
+            latency_ms: latency.as_secs_f64() * 1000.0,
+        };
+        let line = match self.format {
+            QueryLogFormat::Json => match serde_json::to_string(&entry) {
+                Ok(line) => line,
+                Err(e) => {
+                    warn!("Failed to log the query of {}: {}", entry.qname, e);
+                    return;
+                }
+            },
+            QueryLogFormat::Csv => entry.csv(),
+        };
+        self.send(line);
+    }
+
+    fn send(&self, line: String) {
+        match self.lines.try_send(line) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => self.metrics.query_log_dropped("full"),
+            Err(TrySendError::Disconnected(_)) => self.metrics.query_log_dropped("closed"),
+        }
+    }
+}
+
+struct Writer {
+    config: QueryLogConfig,
+    file: BufWriter<File>,
+    size: u64,
+    opened: Instant,
+}
+
+impl Writer {
+    fn open(config: QueryLogConfig) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let mut writer = Writer {
+            size: file.metadata()?.len(),
+            file: BufWriter::new(file),
+            config,
+            opened: Instant::now(),
+        };
+        if writer.size == 0 && writer.config.format == QueryLogFormat::Csv {
+            writer.write_line(CSV_HEADER)?;
+        }
+        Ok(writer)
+    }
+
+    fn run(&mut self, lines: Receiver<String>) {
+        while let Ok(line) = lines.recv() {
+            // flush once the lines waiting are written
+            let result = std::iter::once(line)
+                .chain(lines.try_iter())
+                .try_for_each(|line| self.write(&line))
+                .and_then(|_| self.file.flush());
+            if let Err(e) = result {
+                warn!(
+                    "Failed to write the query log {}: {}",
+                    self.config.path.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        let full = self
+            .config
+            .max_size
+            .is_some_and(|max_size| self.size >= max_size);
+        let expired = self
+            .config
+            .interval
+            .is_some_and(|interval| self.opened.elapsed() >= Duration::from_secs(interval));
+        if full || expired {
+            self.rotate()?;
+        }
+        self.write_line(line)
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    /// Move the file to `<path>.1`, the older ones one further, and start a new one
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let keep = self.config.keep.unwrap_or(5);
+        let path = &self.config.path;
+        let _ = fs::remove_file(rotated(path, keep));
+        for i in (1..keep).rev() {
+            match fs::rename(rotated(path, i), rotated(path, i + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        }
+        if keep > 0 {
+            fs::rename(path, rotated(path, 1))?;
+        } else {
+            fs::remove_file(path)?;
+        }
+        *self = Writer::open(self.config.clone())?;
+        Ok(())
+    }
+}
+
+fn rotated(path: &Path, index: usize) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{}", index));
+    path.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> Entry {
+        Entry {
+            timestamp: "2024-01-01T00:00:00.000Z".to_string(),
+            client: "127.0.0.1:53000".parse().unwrap(),
+            listener: "udp".to_string(),
+            qname: "example.com.".to_string(),
+            qtype: "A".to_string(),
+            route: Route {
+                rule: Some(1),
+                upstreams: vec!["google".to_string(), "local".to_string()],
+                winner: Some("local".to_string()),
+                dropped: vec![Dropped {
+                    upstream: "google".to_string(),
+                    rule: Some(0),
+                }],
+            },
+            rcode: "NOERROR".to_string(),
+            answers: vec!["example.com. 60 IN TXT \"a,b\"".to_string()],
+            latency_ms: 1.5,
+        }
+    }
+
+    #[test]
+    fn entry_formats() {
+        let entry = entry();
+        assert_eq!(
+            entry.csv(),
+            "2024-01-01T00:00:00.000Z,127.0.0.1:53000,udp,example.com.,A,1,google;local,local,\
+             google:0,NOERROR,\"example.com. 60 IN TXT \"\"a,b\"\"\",1.5"
+        );
+        let json: serde_json::Value = serde_json::to_value(&entry).unwrap();
+        assert_eq!(json["rule"], 1);
+        assert_eq!(json["winner"], "local");
+        assert_eq!(json["dropped"][0]["upstream"], "google");
+        assert_eq!(json["latency_ms"], 1.5);
+    }
+
+    #[test]
+    fn drop_lines() {
+        let (lines, receiver) = mpsc::sync_channel(1);
+        let metrics = Arc::new(Metrics::default());
+        let query_log = QueryLog {
+            format: QueryLogFormat::Json,
+            lines,
+            metrics: metrics.clone(),
+        };
+        query_log.send("first".to_string());
+        query_log.send("second".to_string());
+        drop(receiver);
+        query_log.send("third".to_string());
+        let text = metrics.render(&Default::default());
+        assert!(text.contains("yadns_query_log_dropped_total{reason=\"full\"} 1"));
+        assert!(text.contains("yadns_query_log_dropped_total{reason=\"closed\"} 1"));
+    }
+
+    #[test]
+    fn rotate_by_size() {
+        let dir = std::env::temp_dir().join(format!("yadns-query-log-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("queries.csv");
+        let mut writer = Writer::open(QueryLogConfig {
+            path: path.clone(),
+            format: QueryLogFormat::Csv,
+            max_size: Some(200),
+            interval: None,
+            keep: Some(2),
+            buffer: None,
+        })
+        .unwrap();
+        let line = "x".repeat(99);
+        for _ in 0..8 {
+            writer.write(&line).unwrap();
+        }
+        writer.file.flush().unwrap();
+
+        let lines = |path: &Path| fs::read_to_string(path).unwrap().lines().count();
+        // a file takes the header and two lines of 100 bytes before it reaches 200 bytes,
+        // so the lines fill four files and the oldest one past the two kept is removed
+        assert_eq!(lines(&path), 3);
+        assert_eq!(lines(&rotated(&path, 1)), 3);
+        assert_eq!(lines(&rotated(&path, 2)), 3);
+        assert!(!rotated(&path, 3).exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
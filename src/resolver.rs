@@ -1,104 +1,515 @@
-use hickory_proto::rr::RecordType;
-use hickory_proto::xfer::Protocol;
+use async_trait::async_trait;
+use hickory_proto::op::{Message, MessageType, OpCode, Query};
+use hickory_proto::rr::rdata::opt::ClientSubnet;
+use hickory_proto::rr::{Name, Record, RecordType};
+use hickory_proto::xfer::{DnsResponse, Protocol};
+use hickory_proto::{ProtoError, ProtoErrorKind};
 use hickory_resolver::config::{NameServerConfig, ResolverConfig, ResolverOpts};
 use hickory_resolver::lookup::Lookup;
 use hickory_resolver::{ResolveError, Resolver};
+use log::warn;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 use crate::config::{ResolverOpts as MyResolverOpts, Upstream};
+use crate::ecs::{self, EcsPolicy, QueryClient};
+use crate::metrics::CacheStats;
+use crate::resolver_forward::Forwarder;
+use crate::resolver_proxy::{Proxy, ProxyHostResolver};
 use crate::resolver_runtime_provider::{ProxyConnectionProvider, ProxyRuntimeProvider};
+use crate::resolver_system;
+use crate::sections::{self, Sections};
+
+/// A query name and type, with the subnet sent for it
+type CacheKey = (String, RecordType, Option<ClientSubnet>);
+
+/// A lookup with the sections of its response, or the error of a negative response
+type CachedLookup = Result<(Lookup, Sections), ResolveError>;
+
+/// The cached lookups, the one expiring first makes room when it is full
+#[derive(Debug, Default)]
+struct LookupCache {
+    entries: HashMap<CacheKey, (CachedLookup, (Instant, u64))>,
+    // the keys by expiry, a sequence number tells apart the ones expiring together
+    expiries: BTreeMap<(Instant, u64), CacheKey>,
+    sequence: u64,
+}
+
+impl LookupCache {
+    fn get(&self, key: &CacheKey, now: Instant) -> Option<CachedLookup> {
+        self.entries
+            .get(key)
+            .filter(|(_, (valid_until, _))| *valid_until > now)
+            .map(|(lookup, _)| lookup.clone())
+    }
+
+    fn insert(&mut self, key: CacheKey, lookup: CachedLookup, valid_until: Instant, size: usize) {
+        if size == 0 {
+            return;
+        }
+        if let Some((_, expiry)) = self.entries.remove(&key) {
+            self.expiries.remove(&expiry);
+        }
+        while self.entries.len() >= size
+            && let Some((_, expires_first)) = self.expiries.pop_first()
+        {
+            self.entries.remove(&expires_first);
+        }
+        self.sequence += 1;
+        let expiry = (valid_until, self.sequence);
+        self.expiries.insert(expiry, key.clone());
+        self.entries.insert(key, (lookup, expiry));
+    }
+
+    fn flush(&mut self, domain: Option<&str>) {
+        match domain {
+            Some(domain) => {
+                let flushed = |(name, _, _): &CacheKey| name.eq_ignore_ascii_case(domain);
+                self.entries.retain(|key, _| !flushed(key));
+                self.expiries.retain(|_, key| !flushed(key));
+            }
+            None => {
+                self.entries.clear();
+                self.expiries.clear();
+            }
+        }
+    }
+}
+
+/// How long a negative response is cached, from the SOA of its authority section
+fn negative_ttl(error: &ResolveError) -> Option<u32> {
+    match error.proto()?.kind() {
+        ProtoErrorKind::NoRecordsFound { negative_ttl, .. } => *negative_ttl,
+        _ => None,
+    }
+}
+
+/// An upstream the handler sends queries to. [`RecursiveResolver`] implements it for
+/// the upstreams of the config, others can be added with
+/// [`HandlerConfig::with_resolver`](crate::HandlerConfig::with_resolver).
+#[async_trait]
+pub trait UpstreamResolver: Debug + Send + Sync {
+    /// Send `message` as it is and return the response, telling the upstream about
+    /// `client` if it cares. Negative responses are responses, not errors.
+    async fn forward(
+        &self,
+        message: &Message,
+        client: Option<&QueryClient>,
+    ) -> Result<Message, ProtoError>;
+
+    /// Resolve `domain` with the authority and additional sections of the response.
+    /// Responses without answers are `NoRecordsFound` errors, like hickory's.
+    async fn resolve_with_sections(
+        &self,
+        domain: &str,
+        record_type: RecordType,
+        client: Option<&QueryClient>,
+    ) -> Result<(Lookup, Sections), ResolveError> {
+        let query = Query::query(Name::from_str(domain)?, record_type);
+        let mut message = Message::new();
+        message
+            .set_id(rand::random())
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true)
+            .add_query(query.clone());
+        let response = self.forward(&message, client).await?;
+        let response = ProtoError::from_response(DnsResponse::from_message(response)?, false)?;
+        let mut response = response.into_message();
+        let ttl = response
+            .answers()
+            .iter()
+            .map(Record::ttl)
+            .min()
+            .unwrap_or(0);
+        let valid_until = Instant::now() + Duration::from_secs(ttl.into());
+        let sections = Sections {
+            name_servers: response.take_name_servers(),
+            additionals: response.take_additionals(),
+        };
+        let records = response.take_answers().into();
+        Ok((
+            Lookup::new_with_deadline(query, records, valid_until),
+            sections,
+        ))
+    }
+
+    /// Resolve `domain` like [`Self::resolve_with_sections`], without the sections.
+    async fn resolve(
+        &self,
+        domain: &str,
+        record_type: RecordType,
+        client: Option<&QueryClient>,
+    ) -> Result<Lookup, ResolveError> {
+        let (lookup, _) = self
+            .resolve_with_sections(domain, record_type, client)
+            .await?;
+        Ok(lookup)
+    }
+
+    /// Forget the cached answers for `domain`, or all of them
+    fn flush_cache(&self, _domain: Option<&str>) {}
+
+    /// The hits and misses of the cache, none without one
+    fn cache_stats(&self) -> Option<&CacheStats> {
+        None
+    }
+
+    /// Whether the answers are validated with DNSSEC
+    fn validates(&self) -> bool {
+        false
+    }
+}
+
+/// The clients of the name servers of an upstream
+#[derive(Debug, Clone)]
+struct Clients {
+    resolver: Resolver<ProxyConnectionProvider>,
+    forwarder: Forwarder,
+}
 
 #[derive(Debug)]
 pub struct RecursiveResolver {
-    pub resolver: Resolver<ProxyConnectionProvider>,
+    // replaced when the name servers of a system upstream change
+    clients: Arc<RwLock<Clients>>,
     pub options: MyResolverOpts,
+    ecs: EcsPolicy,
+    // hickory caches the answers by query only, without the client subnet and the other
+    // sections of the responses, so its cache is off and they are all cached here
+    cache: Mutex<LookupCache>,
+    pub cache_stats: CacheStats,
 }
 
 impl RecursiveResolver {
     pub fn new(
         resolver_config: ResolverConfig,
         resolver_opts: Option<MyResolverOpts>,
+        ecs: EcsPolicy,
         provider: ProxyConnectionProvider,
     ) -> Self {
-        let mut opts = ResolverOpts::default();
-        let options = resolver_opts.unwrap_or(MyResolverOpts {
-            timeout: opts.timeout,
+        let options = resolver_opts.unwrap_or_else(Self::default_options);
+        let clients = Self::build_clients(resolver_config, &options, provider);
+        RecursiveResolver {
+            clients: Arc::new(RwLock::new(clients)),
+            options,
+            ecs,
+            cache: Mutex::default(),
+            cache_stats: CacheStats::default(),
+        }
+    }
+
+    fn default_options() -> MyResolverOpts {
+        let defaults = ResolverOpts::default();
+        MyResolverOpts {
+            timeout: defaults.timeout,
             ip_strategy: None,
-            cache_size: opts.cache_size,
-        });
+            cache_size: defaults.cache_size,
+            dnssec: false,
+        }
+    }
+
+    fn build_clients(
+        resolver_config: ResolverConfig,
+        options: &MyResolverOpts,
+        provider: ProxyConnectionProvider,
+    ) -> Clients {
+        let mut opts = ResolverOpts::default();
         opts.timeout = options.timeout;
         opts.ip_strategy = options.ip_strategy.unwrap_or_default();
-        opts.cache_size = options.cache_size;
+        opts.cache_size = 0;
+        opts.validate = options.dnssec;
+        let forwarder = Forwarder::new(&resolver_config, opts.clone(), provider.clone());
         let mut builder = Resolver::builder_with_config(resolver_config, provider);
         *builder.options_mut() = opts;
-        let resolver = builder.build();
-        RecursiveResolver { resolver, options }
+        Clients {
+            resolver: builder.build(),
+            forwarder,
+        }
     }
 
-    pub async fn resolve(
+    /// Rebuild the resolver whenever the name servers in `path` change.
+    fn watch_resolv_conf(
+        &self,
+        path: PathBuf,
+        name_servers: Vec<SocketAddr>,
+        udp: bool,
+        provider: ProxyConnectionProvider,
+    ) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            warn!("{} is not watched outside of a runtime", path.display());
+            return;
+        };
+        let clients = Arc::downgrade(&self.clients);
+        let options = self.options;
+        runtime.spawn(resolver_system::watch_resolv_conf(
+            path,
+            name_servers,
+            move |name_servers| {
+                let Some(clients) = clients.upgrade() else {
+                    return false;
+                };
+                let resolver_config = resolver_system::resolver_config(name_servers, udp);
+                *clients.write().unwrap() =
+                    Self::build_clients(resolver_config, &options, provider.clone());
+                true
+            },
+        ));
+    }
+
+    async fn lookup_records(
         &self,
         domain: &str,
         record_type: RecordType,
     ) -> Result<Lookup, ResolveError> {
+        let resolver = self.clients.read().unwrap().resolver.clone();
         match record_type {
             RecordType::A | RecordType::AAAA => match self.options.ip_strategy {
-                Some(_) => match self.resolver.lookup_ip(domain).await {
+                Some(_) => match resolver.lookup_ip(domain).await {
                     Ok(res) => Ok(res.into()),
                     Err(e) => Err(e),
                 },
-                None => self.resolver.lookup(domain, record_type).await,
+                None => resolver.lookup(domain, record_type).await,
             },
-            _ => self.resolver.lookup(domain, record_type).await,
+            _ => resolver.lookup(domain, record_type).await,
         }
     }
 }
 
+#[async_trait]
+impl UpstreamResolver for RecursiveResolver {
+    async fn forward(
+        &self,
+        message: &Message,
+        client: Option<&QueryClient>,
+    ) -> Result<Message, ProtoError> {
+        let forwarder = self.clients.read().unwrap().forwarder.clone();
+        let response =
+            ecs::with_subnet(self.ecs.subnet(client), forwarder.forward(message)).await?;
+        Ok(response.into_message())
+    }
+
+    async fn resolve_with_sections(
+        &self,
+        domain: &str,
+        record_type: RecordType,
+        client: Option<&QueryClient>,
+    ) -> Result<(Lookup, Sections), ResolveError> {
+        let subnet = self.ecs.subnet(client);
+        let key = (domain.to_string(), record_type, subnet);
+        if let Some(cached) = self.cache.lock().unwrap().get(&key, Instant::now()) {
+            self.cache_stats.hit();
+            return cached;
+        }
+        self.cache_stats.miss();
+        let (lookup, sections) = sections::capture(
+            record_type,
+            ecs::with_subnet(subnet, self.lookup_records(domain, record_type)),
+        )
+        .await;
+        let (lookup, valid_until) = match lookup {
+            Ok(lookup) => {
+                let valid_until = lookup.valid_until();
+                (Ok((lookup, sections.unwrap_or_default())), valid_until)
+            }
+            Err(e) => match negative_ttl(&e) {
+                Some(ttl) => (Err(e), Instant::now() + Duration::from_secs(ttl.into())),
+                None => return Err(e),
+            },
+        };
+        self.cache.lock().unwrap().insert(
+            key,
+            lookup.clone(),
+            valid_until,
+            self.options.cache_size,
+        );
+        lookup
+    }
+
+    fn flush_cache(&self, domain: Option<&str>) {
+        self.cache.lock().unwrap().flush(domain);
+    }
+
+    fn cache_stats(&self) -> Option<&CacheStats> {
+        Some(&self.cache_stats)
+    }
+
+    fn validates(&self) -> bool {
+        self.options.dnssec
+    }
+}
+
 impl From<(&Upstream, Option<MyResolverOpts>)> for RecursiveResolver {
     fn from((upstream, config): (&Upstream, Option<MyResolverOpts>)) -> Self {
-        let (protocol, address, tls_host, proxy) = match upstream {
-            Upstream::UdpUpstream { address, proxy } => (Protocol::Udp, address, None, proxy),
-            Upstream::TcpUpstream { address, proxy } => (Protocol::Tcp, address, None, proxy),
+        Self::from_upstream(upstream, config, Default::default())
+    }
+}
+
+impl RecursiveResolver {
+    /// Build the resolver of `upstream`, its proxy host names are resolved by `proxy_hosts`.
+    pub fn from_upstream(
+        upstream: &Upstream,
+        config: Option<MyResolverOpts>,
+        proxy_hosts: Arc<ProxyHostResolver>,
+    ) -> Self {
+        let (protocol, address, tls_host, proxy, bind): (_, _, Option<&str>, _, _) = match upstream
+        {
+            Upstream::UdpUpstream {
+                address,
+                proxy,
+                bind,
+                ..
+            } => (Protocol::Udp, address, None, proxy, bind),
+            Upstream::TcpUpstream {
+                address,
+                proxy,
+                bind,
+                ..
+            } => (Protocol::Tcp, address, None, proxy, bind),
             #[cfg(feature = "dns-over-tls")]
             Upstream::TlsUpstream {
                 address,
                 tls_host,
                 proxy,
-            } => (Protocol::Tls, address, Some(tls_host.as_str()), proxy),
+                bind,
+                ..
+            } => (Protocol::Tls, address, Some(tls_host.as_str()), proxy, bind),
             #[cfg(feature = "dns-over-https")]
             Upstream::HttpsUpstream {
                 address,
                 tls_host,
                 proxy,
-            } => (Protocol::Https, address, Some(tls_host.as_str()), proxy),
+                bind,
+                ..
+            } => (
+                Protocol::Https,
+                address,
+                Some(tls_host.as_str()),
+                proxy,
+                bind,
+            ),
             #[cfg(feature = "dns-over-h3")]
             Upstream::H3Upstream {
                 address,
                 tls_host,
                 proxy,
-            } => (Protocol::H3, address, Some(tls_host.as_str()), proxy),
+                bind,
+                ..
+            } => (Protocol::H3, address, Some(tls_host.as_str()), proxy, bind),
             #[cfg(feature = "dns-over-quic")]
             Upstream::QuicUpstream {
                 address,
                 tls_host,
                 proxy,
-            } => (Protocol::Quic, address, Some(tls_host.as_str()), proxy),
+                bind,
+                ..
+            } => (
+                Protocol::Quic,
+                address,
+                Some(tls_host.as_str()),
+                proxy,
+                bind,
+            ),
+            #[cfg(feature = "dnscrypt")]
+            Upstream::DnsCryptUpstream {
+                address,
+                proxy,
+                bind,
+                ..
+            } => (Protocol::Udp, address, None, proxy, bind),
+            Upstream::SystemUpstream { proxy, bind, .. } => {
+                (Protocol::Udp, &Vec::new(), None, proxy, bind)
+            }
+        };
+        #[cfg(any(feature = "dns-over-https", feature = "dns-over-h3"))]
+        let (http_endpoint, http_headers) = match upstream {
+            #[cfg(feature = "dns-over-https")]
+            Upstream::HttpsUpstream { path, headers, .. } => (path.clone(), headers.clone()),
+            #[cfg(feature = "dns-over-h3")]
+            Upstream::H3Upstream { path, headers, .. } => (path.clone(), headers.clone()),
+            _ => (None, Default::default()),
+        };
+        #[cfg(any(
+            feature = "dns-over-tls",
+            feature = "dns-over-https",
+            feature = "dns-over-h3",
+            feature = "dns-over-quic"
+        ))]
+        let cert_hashes = match upstream {
+            #[cfg(feature = "dns-over-tls")]
+            Upstream::TlsUpstream { cert_hashes, .. } => cert_hashes.clone(),
+            #[cfg(feature = "dns-over-https")]
+            Upstream::HttpsUpstream { cert_hashes, .. } => cert_hashes.clone(),
+            #[cfg(feature = "dns-over-h3")]
+            Upstream::H3Upstream { cert_hashes, .. } => cert_hashes.clone(),
+            #[cfg(feature = "dns-over-quic")]
+            Upstream::QuicUpstream { cert_hashes, .. } => cert_hashes.clone(),
+            _ => Vec::new(),
+        };
+        let system = match upstream {
+            Upstream::SystemUpstream { path, .. } => {
+                Some((path, resolver_system::read_resolv_conf(path)))
+            }
+            _ => None,
+        };
+        // only TCP goes through a proxy which can't relay UDP
+        let udp = proxy.as_ref().is_none_or(Proxy::supports_udp);
+        let mut resolver_config = match &system {
+            Some((_, name_servers)) => resolver_system::resolver_config(name_servers, udp),
+            None => ResolverConfig::new(),
         };
-        let mut resolver_config = ResolverConfig::new();
         address.iter().for_each(|addr| {
             let mut name_server_config = NameServerConfig::new(*addr, protocol);
             name_server_config.tls_dns_name = tls_host.map(String::from);
+            #[cfg(any(feature = "dns-over-https", feature = "dns-over-h3"))]
+            {
+                name_server_config.http_endpoint = http_endpoint.clone();
+            }
             resolver_config.add_name_server(name_server_config);
         });
-        let runtime_provider =
-            ProxyRuntimeProvider::new(proxy.as_ref().map(|p| p.parse().unwrap()));
-        let provider = ProxyConnectionProvider::new(runtime_provider);
-        RecursiveResolver::new(resolver_config, config, provider)
+        let runtime_provider = ProxyRuntimeProvider::new(proxy.clone(), bind.clone(), proxy_hosts);
+        #[allow(unused_mut)]
+        let mut provider = ProxyConnectionProvider::new(runtime_provider);
+        #[cfg(any(feature = "dns-over-https", feature = "dns-over-h3"))]
+        if !http_headers.is_empty() {
+            provider = provider.with_http_headers(http_headers);
+        }
+        #[cfg(any(
+            feature = "dns-over-tls",
+            feature = "dns-over-https",
+            feature = "dns-over-h3",
+            feature = "dns-over-quic"
+        ))]
+        if !cert_hashes.is_empty() {
+            provider = provider.with_cert_hashes(cert_hashes);
+        }
+        #[cfg(feature = "dnscrypt")]
+        if let Upstream::DnsCryptUpstream { server, .. } = upstream {
+            provider = provider.with_dnscrypt(server.clone());
+        }
+        let mut options = config.unwrap_or_else(Self::default_options);
+        if let Some(dnssec) = upstream.dnssec() {
+            options.dnssec = dnssec;
+        }
+        let resolver = RecursiveResolver::new(
+            resolver_config,
+            Some(options),
+            upstream.ecs(),
+            provider.clone(),
+        );
+        if let Some((path, name_servers)) = system {
+            resolver.watch_resolv_conf(path.clone(), name_servers, udp, provider);
+        }
+        resolver
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::net::SocketAddr;
-
     use super::*;
 
     #[tokio::test]
@@ -108,11 +519,17 @@ mod tests {
             &Upstream::UdpUpstream {
                 address: vec![dns_addr],
                 proxy: None,
+                bind: Default::default(),
+                ecs: Default::default(),
+                dnssec: None,
             },
             None,
         )
             .into();
-        let response = resolver.resolve("dns.google", RecordType::A).await.unwrap();
+        let response = resolver
+            .resolve("dns.google", RecordType::A, None)
+            .await
+            .unwrap();
         assert!(
             response
                 .record_iter()
@@ -127,11 +544,17 @@ mod tests {
             &Upstream::TcpUpstream {
                 address: vec![dns_addr],
                 proxy: None,
+                bind: Default::default(),
+                ecs: Default::default(),
+                dnssec: None,
             },
             None,
         )
             .into();
-        let response = resolver.resolve("dns.google", RecordType::A).await.unwrap();
+        let response = resolver
+            .resolve("dns.google", RecordType::A, None)
+            .await
+            .unwrap();
         assert!(
             response
                 .record_iter()
@@ -148,12 +571,19 @@ mod tests {
             &Upstream::TlsUpstream {
                 address: vec![dns_addr],
                 proxy: None,
+                bind: Default::default(),
+                ecs: Default::default(),
+                dnssec: None,
                 tls_host: dns_host,
+                cert_hashes: Vec::new(),
             },
             None,
         )
             .into();
-        let response = resolver.resolve("dns.google", RecordType::A).await.unwrap();
+        let response = resolver
+            .resolve("dns.google", RecordType::A, None)
+            .await
+            .unwrap();
         assert!(
             response
                 .record_iter()
@@ -170,12 +600,21 @@ mod tests {
             &Upstream::HttpsUpstream {
                 address: vec![dns_addr],
                 proxy: None,
+                bind: Default::default(),
+                ecs: Default::default(),
+                dnssec: None,
                 tls_host: dns_host,
+                path: None,
+                headers: Default::default(),
+                cert_hashes: Vec::new(),
             },
             None,
         )
             .into();
-        let response = resolver.resolve("dns.google", RecordType::A).await.unwrap();
+        let response = resolver
+            .resolve("dns.google", RecordType::A, None)
+            .await
+            .unwrap();
         assert!(
             response
                 .record_iter()
@@ -192,16 +631,332 @@ mod tests {
             &Upstream::H3Upstream {
                 address: vec![dns_addr],
                 proxy: None,
+                bind: Default::default(),
+                ecs: Default::default(),
+                dnssec: None,
                 tls_host: dns_host,
+                path: None,
+                headers: Default::default(),
+                cert_hashes: Vec::new(),
             },
             None,
         )
             .into();
-        let response = resolver.resolve("dns.google", RecordType::A).await.unwrap();
+        let response = resolver
+            .resolve("dns.google", RecordType::A, None)
+            .await
+            .unwrap();
         assert!(
             response
                 .record_iter()
                 .any(|r| r.data().to_string().eq("8.8.8.8"))
         );
     }
+
+    /// A name server answering A queries with the address of their client subnet,
+    /// counting the queries it gets.
+    async fn subnet_echo_server(queries: Arc<std::sync::atomic::AtomicUsize>) -> SocketAddr {
+        use hickory_proto::op::Message;
+        use hickory_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+        use hickory_proto::rr::rdata::{A, NS, SOA};
+        use hickory_proto::rr::{Name, RData, Record};
+        use std::sync::atomic::Ordering;
+
+        let ns = Name::from_ascii("ns.example.com.").unwrap();
+
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+                queries.fetch_add(1, Ordering::SeqCst);
+                let request = Message::from_vec(&buf[..len]).unwrap();
+                let ip = match request
+                    .extensions()
+                    .as_ref()
+                    .and_then(|edns| edns.option(EdnsCode::Subnet))
+                {
+                    Some(EdnsOption::Subnet(subnet)) => subnet.addr(),
+                    _ => [0, 0, 0, 0].into(),
+                };
+                let std::net::IpAddr::V4(ip) = ip else {
+                    panic!("unexpected subnet {ip}");
+                };
+                let mut response = Message::new();
+                // no AAAA records, for the negative responses
+                if request.queries()[0].query_type() == RecordType::AAAA {
+                    response
+                        .set_id(request.id())
+                        .set_message_type(hickory_proto::op::MessageType::Response)
+                        .set_recursion_desired(true)
+                        .set_recursion_available(true)
+                        .add_queries(request.queries().to_vec())
+                        .add_name_server(Record::from_rdata(
+                            Name::from_ascii("example.com.").unwrap(),
+                            60,
+                            RData::SOA(SOA::new(
+                                ns.clone(),
+                                Name::from_ascii("hostmaster.example.com.").unwrap(),
+                                1,
+                                3600,
+                                600,
+                                86400,
+                                60,
+                            )),
+                        ));
+                    socket
+                        .send_to(&response.to_vec().unwrap(), peer)
+                        .await
+                        .unwrap();
+                    continue;
+                }
+                response
+                    .set_id(request.id())
+                    .set_message_type(hickory_proto::op::MessageType::Response)
+                    .set_recursion_desired(true)
+                    .set_recursion_available(true)
+                    .add_queries(request.queries().to_vec())
+                    .add_answer(Record::from_rdata(
+                        request.queries()[0].name().clone(),
+                        60,
+                        RData::A(A(ip)),
+                    ))
+                    .add_name_server(Record::from_rdata(
+                        request.queries()[0].name().clone(),
+                        60,
+                        RData::NS(NS(ns.clone())),
+                    ))
+                    .add_additional(Record::from_rdata(
+                        ns.clone(),
+                        60,
+                        RData::A(A::new(192, 0, 2, 53)),
+                    ));
+                socket
+                    .send_to(&response.to_vec().unwrap(), peer)
+                    .await
+                    .unwrap();
+            }
+        });
+        addr
+    }
+
+    #[cfg(feature = "dnssec")]
+    #[tokio::test]
+    async fn upstream_dnssec_override() {
+        let upstream = |dnssec| Upstream::UdpUpstream {
+            address: vec!["192.0.2.1:53".parse().unwrap()],
+            proxy: None,
+            bind: Default::default(),
+            ecs: Default::default(),
+            dnssec,
+        };
+        let options = MyResolverOpts {
+            dnssec: true,
+            ..RecursiveResolver::default_options()
+        };
+        let resolver: RecursiveResolver = (&upstream(None), Some(options)).into();
+        assert!(resolver.validates());
+        let resolver: RecursiveResolver = (&upstream(Some(false)), Some(options)).into();
+        assert!(!resolver.validates());
+        let resolver: RecursiveResolver = (&upstream(Some(true)), None).into();
+        assert!(resolver.validates());
+    }
+
+    #[tokio::test]
+    async fn client_subnet_cache() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let queries = Arc::new(AtomicUsize::new(0));
+        let resolver: RecursiveResolver = (
+            &Upstream::UdpUpstream {
+                address: vec![subnet_echo_server(queries.clone()).await],
+                proxy: None,
+                bind: Default::default(),
+                ecs: EcsPolicy::Passthrough,
+                dnssec: None,
+            },
+            None,
+        )
+            .into();
+        let resolve = async |subnet: Option<&str>| {
+            let client = QueryClient {
+                addr: "127.0.0.1".parse().unwrap(),
+                subnet: subnet.map(|subnet| subnet.parse().unwrap()),
+            };
+            let lookup = resolver
+                .resolve("example.com.", RecordType::A, Some(&client))
+                .await
+                .unwrap();
+            lookup.record_iter().next().unwrap().data().to_string()
+        };
+        assert_eq!(resolve(Some("192.0.2.0/24")).await, "192.0.2.0");
+        assert_eq!(resolve(Some("198.51.100.0/24")).await, "198.51.100.0");
+        assert_eq!(resolve(None).await, "0.0.0.0");
+        assert_eq!(resolve(Some("192.0.2.0/24")).await, "192.0.2.0");
+        assert_eq!(queries.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn response_sections() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let queries = Arc::new(AtomicUsize::new(0));
+        let resolver: RecursiveResolver = (
+            &Upstream::UdpUpstream {
+                address: vec![subnet_echo_server(queries.clone()).await],
+                proxy: None,
+                bind: Default::default(),
+                ecs: Default::default(),
+                dnssec: None,
+            },
+            None,
+        )
+            .into();
+        for _ in 0..2 {
+            let (lookup, sections) = resolver
+                .resolve_with_sections("example.com.", RecordType::A, None)
+                .await
+                .unwrap();
+            assert_eq!(lookup.records().len(), 1);
+            assert_eq!(sections.name_servers[0].record_type(), RecordType::NS);
+            assert_eq!(sections.additionals[0].data().to_string(), "192.0.2.53");
+        }
+        // the second one is answered from the cache
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+        assert_eq!(resolver.cache_stats.hits(), 1);
+        assert_eq!(resolver.cache_stats.misses(), 1);
+    }
+
+    #[tokio::test]
+    async fn response_sections_cache_full() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let queries = Arc::new(AtomicUsize::new(0));
+        let options = MyResolverOpts {
+            cache_size: 1,
+            ..RecursiveResolver::default_options()
+        };
+        let resolver: RecursiveResolver = (
+            &Upstream::UdpUpstream {
+                address: vec![subnet_echo_server(queries.clone()).await],
+                proxy: None,
+                bind: Default::default(),
+                ecs: Default::default(),
+                dnssec: None,
+            },
+            Some(options),
+        )
+            .into();
+        // the second name makes room by evicting the first one, which is looked up again
+        for name in [
+            "example.com.",
+            "example.net.",
+            "example.net.",
+            "example.com.",
+        ] {
+            let (_, sections) = resolver
+                .resolve_with_sections(name, RecordType::A, None)
+                .await
+                .unwrap();
+            assert_eq!(sections.name_servers[0].name().to_string(), name);
+        }
+        assert_eq!(queries.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn negative_response_cache() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let queries = Arc::new(AtomicUsize::new(0));
+        let resolver: RecursiveResolver = (
+            &Upstream::UdpUpstream {
+                address: vec![subnet_echo_server(queries.clone()).await],
+                proxy: None,
+                bind: Default::default(),
+                ecs: Default::default(),
+                dnssec: None,
+            },
+            None,
+        )
+            .into();
+        for _ in 0..2 {
+            let error = resolver
+                .resolve_with_sections("example.com.", RecordType::AAAA, None)
+                .await
+                .err()
+                .unwrap();
+            assert!(error.is_no_records_found());
+        }
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+        assert_eq!(resolver.cache_stats.hits(), 1);
+        assert_eq!(resolver.cache_stats.misses(), 1);
+    }
+
+    /// Answers the queries of example.com., and nothing else
+    #[derive(Debug)]
+    struct StaticResolver;
+
+    #[async_trait]
+    impl UpstreamResolver for StaticResolver {
+        async fn forward(
+            &self,
+            message: &Message,
+            _client: Option<&QueryClient>,
+        ) -> Result<Message, ProtoError> {
+            use hickory_proto::op::ResponseCode;
+            use hickory_proto::rr::RData;
+            use hickory_proto::rr::rdata::A;
+
+            let mut response = Message::new();
+            response
+                .set_id(message.id())
+                .set_message_type(MessageType::Response)
+                .add_queries(message.queries().to_vec());
+            let query = &message.queries()[0];
+            if query.name().to_string() == "example.com." {
+                response.add_answer(Record::from_rdata(
+                    query.name().clone(),
+                    60,
+                    RData::A(A::new(192, 0, 2, 1)),
+                ));
+            } else {
+                response.set_response_code(ResponseCode::NXDomain);
+            }
+            Ok(response)
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_resolver() {
+        use crate::config::{ConfigBuilder, NetworkType, UpstreamConfig};
+        use crate::handler_config::HandlerConfig;
+
+        let lookup = StaticResolver
+            .resolve("example.com.", RecordType::A, None)
+            .await
+            .unwrap();
+        assert_eq!(lookup.records()[0].data().to_string(), "192.0.2.1");
+        let error = StaticResolver
+            .resolve("missing.example.", RecordType::A, None)
+            .await
+            .unwrap_err();
+        assert!(error.is_nx_domain());
+
+        let config = || {
+            ConfigBuilder::new("127.0.0.1:5300".parse().unwrap())
+                .upstream(
+                    "local",
+                    UpstreamConfig::new(NetworkType::Udp, ["192.0.2.53"]),
+                )
+                .build()
+                .unwrap()
+        };
+        let handler_config = HandlerConfig::from(config()).with_resolver("static", StaticResolver);
+        assert!(handler_config.resolver("static").is_some());
+        // kept when the config is reloaded
+        let handler_config = handler_config.reload(config());
+        assert!(handler_config.resolver("local").is_some());
+        assert!(handler_config.resolver("static").is_some());
+    }
 }
@@ -0,0 +1,636 @@
+use crypto_box::aead::rand_core::RngCore;
+use crypto_box::aead::{Aead, OsRng};
+use crypto_box::{ChaChaBox, PublicKey, SalsaBox, SecretKey};
+use ed25519_dalek::{Signature, VerifyingKey};
+use futures::stream::{self, BoxStream, StreamExt};
+use hickory_proto::ProtoError;
+use hickory_proto::op::{Message, Query};
+use hickory_proto::rr::{Name, RData, RecordType};
+use hickory_proto::runtime::RuntimeProvider;
+use hickory_proto::udp::DnsUdpSocket;
+use hickory_proto::xfer::{DnsRequest, DnsResponse};
+use log::debug;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+
+use crate::resolver_runtime_provider::ProxyRuntimeProvider;
+
+const CERT_MAGIC: &[u8; 4] = b"DNSC";
+const RESOLVER_MAGIC: &[u8; 8] = b"r6fnvWj8";
+const CERT_LEN: usize = 124;
+const MIN_UDP_QUERY_LEN: usize = 256;
+const MAX_PACKET_LEN: usize = 0xffff;
+const CLIENT_NONCE_LEN: usize = 12;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+/// Certificates are fetched again after this interval even if they are still valid,
+/// so that resolvers rotating their keys are picked up.
+const CERT_REFRESH_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// The encryption system of a certificate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum EsVersion {
+    XSalsa20Poly1305,
+    XChaCha20Poly1305,
+}
+
+#[derive(Debug)]
+struct Certificate {
+    es_version: EsVersion,
+    resolver_pk: [u8; 32],
+    client_magic: [u8; 8],
+    serial: u32,
+    ts_start: u32,
+    ts_end: u32,
+}
+
+impl Certificate {
+    fn parse(data: &[u8], provider_key: &VerifyingKey) -> Result<Self, ProtoError> {
+        if data.len() < CERT_LEN || &data[..4] != CERT_MAGIC {
+            return Err(ProtoError::from("invalid dnscrypt certificate"));
+        }
+        let es_version = match u16::from_be_bytes([data[4], data[5]]) {
+            1 => EsVersion::XSalsa20Poly1305,
+            2 => EsVersion::XChaCha20Poly1305,
+            v => {
+                return Err(ProtoError::from(format!(
+                    "unsupported dnscrypt encryption system {v}"
+                )));
+            }
+        };
+        let signature = Signature::from_bytes(data[8..72].try_into().unwrap());
+        provider_key
+            .verify_strict(&data[72..], &signature)
+            .map_err(|_| ProtoError::from("dnscrypt certificate signature mismatch"))?;
+        let u32_at = |i: usize| u32::from_be_bytes(data[i..i + 4].try_into().unwrap());
+        Ok(Certificate {
+            es_version,
+            resolver_pk: data[72..104].try_into().unwrap(),
+            client_magic: data[104..112].try_into().unwrap(),
+            serial: u32_at(112),
+            ts_start: u32_at(116),
+            ts_end: u32_at(120),
+        })
+    }
+
+    fn is_valid(&self, now: u32) -> bool {
+        self.ts_start <= now && now < self.ts_end
+    }
+}
+
+enum Cipher {
+    Salsa(SalsaBox),
+    ChaCha(ChaChaBox),
+}
+
+/// A certificate and the client key pair used with it
+struct Session {
+    cert: Certificate,
+    public_key: PublicKey,
+    cipher: Cipher,
+    created: Instant,
+}
+
+impl Session {
+    fn new(cert: Certificate) -> Self {
+        let secret_key = SecretKey::generate(&mut OsRng);
+        let resolver_pk = PublicKey::from(cert.resolver_pk);
+        let cipher = match cert.es_version {
+            EsVersion::XSalsa20Poly1305 => Cipher::Salsa(SalsaBox::new(&resolver_pk, &secret_key)),
+            EsVersion::XChaCha20Poly1305 => {
+                Cipher::ChaCha(ChaChaBox::new(&resolver_pk, &secret_key))
+            }
+        };
+        Session {
+            cert,
+            public_key: secret_key.public_key(),
+            cipher,
+            created: Instant::now(),
+        }
+    }
+
+    fn is_fresh(&self) -> bool {
+        self.cert.is_valid(unix_time()) && self.created.elapsed() < CERT_REFRESH_INTERVAL
+    }
+
+    /// Build an encrypted query packet padded to at least `min_len` bytes.
+    fn encrypt(
+        &self,
+        query: &[u8],
+        min_len: usize,
+    ) -> Result<(Vec<u8>, [u8; CLIENT_NONCE_LEN]), ProtoError> {
+        let mut client_nonce = [0u8; CLIENT_NONCE_LEN];
+        OsRng.fill_bytes(&mut client_nonce);
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[..CLIENT_NONCE_LEN].copy_from_slice(&client_nonce);
+
+        let padded_len = (query.len() + 1).max(min_len).next_multiple_of(64);
+        let mut padded = Vec::with_capacity(padded_len);
+        padded.extend_from_slice(query);
+        padded.push(0x80);
+        padded.resize(padded_len, 0);
+
+        let encrypted = match &self.cipher {
+            Cipher::Salsa(cipher) => cipher.encrypt(&nonce.into(), padded.as_slice()),
+            Cipher::ChaCha(cipher) => cipher.encrypt(&nonce.into(), padded.as_slice()),
+        }
+        .map_err(|_| ProtoError::from("dnscrypt encryption failed"))?;
+
+        let mut packet = Vec::with_capacity(8 + 32 + CLIENT_NONCE_LEN + encrypted.len());
+        packet.extend_from_slice(&self.cert.client_magic);
+        packet.extend_from_slice(self.public_key.as_bytes());
+        packet.extend_from_slice(&client_nonce);
+        packet.extend_from_slice(&encrypted);
+        Ok((packet, client_nonce))
+    }
+
+    fn decrypt(
+        &self,
+        packet: &[u8],
+        client_nonce: &[u8; CLIENT_NONCE_LEN],
+    ) -> Result<Vec<u8>, ProtoError> {
+        let header_len = RESOLVER_MAGIC.len() + NONCE_LEN;
+        if packet.len() < header_len + TAG_LEN || &packet[..8] != RESOLVER_MAGIC {
+            return Err(ProtoError::from("invalid dnscrypt response"));
+        }
+        let nonce: [u8; NONCE_LEN] = packet[8..header_len].try_into().unwrap();
+        if nonce[..CLIENT_NONCE_LEN] != client_nonce[..] {
+            return Err(ProtoError::from("dnscrypt response nonce mismatch"));
+        }
+        let encrypted = &packet[header_len..];
+        let padded = match &self.cipher {
+            Cipher::Salsa(cipher) => cipher.decrypt(&nonce.into(), encrypted),
+            Cipher::ChaCha(cipher) => cipher.decrypt(&nonce.into(), encrypted),
+        }
+        .map_err(|_| ProtoError::from("dnscrypt decryption failed"))?;
+        unpad(padded)
+    }
+}
+
+fn unpad(mut padded: Vec<u8>) -> Result<Vec<u8>, ProtoError> {
+    while let Some(0) = padded.last() {
+        padded.pop();
+    }
+    match padded.pop() {
+        Some(0x80) => Ok(padded),
+        _ => Err(ProtoError::from("invalid dnscrypt padding")),
+    }
+}
+
+fn unix_time() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or_default()
+}
+
+/// The provider of a DNSCrypt upstream, shared by all of its connections.
+pub struct DnsCryptServer {
+    provider_name: Name,
+    provider_key: VerifyingKey,
+    sessions: Mutex<HashMap<SocketAddr, Arc<Session>>>,
+}
+
+impl fmt::Debug for DnsCryptServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DnsCryptServer")
+            .field("provider_name", &self.provider_name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DnsCryptServer {
+    pub fn new(provider_name: &str, public_key: &[u8; 32]) -> Result<Self, ProtoError> {
+        Ok(Self {
+            provider_name: Name::from_str(provider_name)?,
+            provider_key: VerifyingKey::from_bytes(public_key)
+                .map_err(|_| ProtoError::from("invalid dnscrypt provider public key"))?,
+            sessions: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct DnsCryptConnection {
+    server: Arc<DnsCryptServer>,
+    runtime_provider: ProxyRuntimeProvider,
+    name_server: SocketAddr,
+    timeout: Duration,
+}
+
+impl DnsCryptConnection {
+    pub fn new(
+        server: Arc<DnsCryptServer>,
+        runtime_provider: ProxyRuntimeProvider,
+        name_server: SocketAddr,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            server,
+            runtime_provider,
+            name_server,
+            timeout,
+        }
+    }
+
+    pub fn send<R: Into<DnsRequest> + Unpin + Send + 'static>(
+        &self,
+        request: R,
+    ) -> BoxStream<'static, Result<DnsResponse, ProtoError>> {
+        let this = self.clone();
+        let request = request.into();
+        stream::once(async move { this.inner_send(request).await }).boxed()
+    }
+
+    async fn inner_send(self, request: DnsRequest) -> Result<DnsResponse, ProtoError> {
+        let session = self.session().await?;
+        let query = request.to_vec()?;
+        // a proxy which can't relay UDP only carries TCP
+        if self.runtime_provider.supports_udp() {
+            let (packet, client_nonce) = session.encrypt(&query, MIN_UDP_QUERY_LEN)?;
+            let mut rejected = false;
+            let response = self
+                .udp_exchange(&packet, |response| {
+                    let decrypted = session.decrypt(response, &client_nonce);
+                    rejected |= decrypted.is_err();
+                    decrypted
+                })
+                .await;
+            match response {
+                Ok(response) => {
+                    let response = DnsResponse::from_buffer(response)?;
+                    if !response.truncated() {
+                        return Ok(response);
+                    }
+                    debug!("dnscrypt response truncated, retrying over TCP");
+                }
+                // forged datagrams or a key rotation, TCP tells them apart
+                Err(e) if rejected => {
+                    debug!("no dnscrypt response decrypted, retrying over TCP: {e}")
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        // random padding hides the query length on TCP
+        let min_len = query.len() + 1 + (OsRng.next_u32() % 4) as usize * 64;
+        let (packet, client_nonce) = session.encrypt(&query, min_len)?;
+        let response = self.tcp_exchange(&packet).await?;
+        self.decrypt(&session, &response, &client_nonce)
+    }
+
+    fn decrypt(
+        &self,
+        session: &Session,
+        packet: &[u8],
+        client_nonce: &[u8; CLIENT_NONCE_LEN],
+    ) -> Result<DnsResponse, ProtoError> {
+        match session.decrypt(packet, client_nonce) {
+            Ok(response) => DnsResponse::from_buffer(response),
+            Err(e) => {
+                // the resolver may have rotated its keys, fetch the certificate again
+                self.server
+                    .sessions
+                    .lock()
+                    .unwrap()
+                    .remove(&self.name_server);
+                Err(e)
+            }
+        }
+    }
+
+    async fn session(&self) -> Result<Arc<Session>, ProtoError> {
+        if let Some(session) = self.server.sessions.lock().unwrap().get(&self.name_server)
+            && session.is_fresh()
+        {
+            return Ok(session.clone());
+        }
+        let session = Arc::new(Session::new(self.fetch_certificate().await?));
+        self.server
+            .sessions
+            .lock()
+            .unwrap()
+            .insert(self.name_server, session.clone());
+        Ok(session)
+    }
+
+    async fn fetch_certificate(&self) -> Result<Certificate, ProtoError> {
+        debug!(
+            "fetching dnscrypt certificate {} from {}",
+            self.server.provider_name, self.name_server
+        );
+        let mut message = Message::new();
+        message
+            .set_id(OsRng.next_u32() as u16)
+            .set_recursion_desired(true)
+            .add_query(Query::query(
+                self.server.provider_name.clone(),
+                RecordType::TXT,
+            ));
+        let query = message.to_vec()?;
+        let mut response = if self.runtime_provider.supports_udp() {
+            self.udp_exchange(&query, |response| {
+                let response = Message::from_vec(response)?;
+                if response.id() != message.id() {
+                    return Err(ProtoError::from(
+                        "dnscrypt certificate response id mismatch",
+                    ));
+                }
+                Ok(response)
+            })
+            .await?
+        } else {
+            Message::from_vec(&self.tcp_exchange(&query).await?)?
+        };
+        if response.truncated() {
+            response = Message::from_vec(&self.tcp_exchange(&query).await?)?;
+        }
+        let now = unix_time();
+        response
+            .answers()
+            .iter()
+            .filter_map(|record| match record.data() {
+                RData::TXT(txt) => Some(txt.txt_data().concat()),
+                _ => None,
+            })
+            .filter_map(
+                |data| match Certificate::parse(&data, &self.server.provider_key) {
+                    Ok(cert) => Some(cert),
+                    Err(e) => {
+                        debug!("ignoring dnscrypt certificate: {}", e);
+                        None
+                    }
+                },
+            )
+            .filter(|cert| cert.is_valid(now))
+            .max_by_key(|cert| (cert.serial, cert.es_version))
+            .ok_or_else(|| ProtoError::from("no valid dnscrypt certificate"))
+    }
+
+    /// Send `packet` over UDP and wait for the first datagram from the name server
+    /// which `accept` takes, skipping the others until the timeout.
+    async fn udp_exchange<T>(
+        &self,
+        packet: &[u8],
+        mut accept: impl FnMut(&[u8]) -> Result<T, ProtoError>,
+    ) -> Result<T, ProtoError> {
+        let local_addr = match self.name_server {
+            SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+        };
+        let exchange = async {
+            let socket = self
+                .runtime_provider
+                .bind_udp(local_addr, self.name_server)
+                .await?;
+            socket.send_to(packet, self.name_server).await?;
+            let mut buf = vec![0u8; MAX_PACKET_LEN];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await?;
+                if from != self.name_server {
+                    continue;
+                }
+                match accept(&buf[..len]) {
+                    Ok(response) => return Ok::<_, ProtoError>(response),
+                    Err(e) => debug!("dropped a datagram from {from}: {e}"),
+                }
+            }
+        };
+        timeout(self.timeout, exchange)
+            .await
+            .map_err(|_| ProtoError::from("dnscrypt request timed out"))?
+    }
+
+    async fn tcp_exchange(&self, packet: &[u8]) -> Result<Vec<u8>, ProtoError> {
+        let exchange = async {
+            let mut stream = self
+                .runtime_provider
+                .connect_tcp(self.name_server, None, Some(self.timeout))
+                .await?
+                .0;
+            let mut buf = Vec::with_capacity(2 + packet.len());
+            buf.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+            buf.extend_from_slice(packet);
+            stream.write_all(&buf).await?;
+            let len = stream.read_u16().await?;
+            let mut response = vec![0u8; len as usize];
+            stream.read_exact(&mut response).await?;
+            Ok::<_, ProtoError>(response)
+        };
+        timeout(self.timeout, exchange)
+            .await
+            .map_err(|_| ProtoError::from("dnscrypt request timed out"))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn certificate(signing_key: &SigningKey, es_version: u16, resolver_pk: &PublicKey) -> Vec<u8> {
+        let now = unix_time();
+        let mut signed = Vec::new();
+        signed.extend_from_slice(resolver_pk.as_bytes());
+        signed.extend_from_slice(b"magicnum");
+        signed.extend_from_slice(&1u32.to_be_bytes());
+        signed.extend_from_slice(&(now - 60).to_be_bytes());
+        signed.extend_from_slice(&(now + 3600).to_be_bytes());
+        let mut cert = Vec::new();
+        cert.extend_from_slice(CERT_MAGIC);
+        cert.extend_from_slice(&es_version.to_be_bytes());
+        cert.extend_from_slice(&[0, 0]);
+        cert.extend_from_slice(&signing_key.sign(&signed).to_bytes());
+        cert.extend_from_slice(&signed);
+        cert
+    }
+
+    #[test]
+    fn certificate_signature() {
+        let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let resolver_sk = SecretKey::from([9u8; 32]);
+        let mut cert = certificate(&signing_key, 2, &resolver_sk.public_key());
+        let parsed = Certificate::parse(&cert, &signing_key.verifying_key()).unwrap();
+        assert_eq!(parsed.es_version, EsVersion::XChaCha20Poly1305);
+        assert_eq!(&parsed.client_magic, b"magicnum");
+        assert!(parsed.is_valid(unix_time()));
+
+        let last = cert.len() - 1;
+        cert[last] ^= 1;
+        assert!(Certificate::parse(&cert, &signing_key.verifying_key()).is_err());
+    }
+
+    #[test]
+    fn encrypt_and_decrypt() {
+        for es_version in [1, 2] {
+            let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+            let resolver_sk = SecretKey::from([9u8; 32]);
+            let cert = certificate(&signing_key, es_version, &resolver_sk.public_key());
+            let session =
+                Session::new(Certificate::parse(&cert, &signing_key.verifying_key()).unwrap());
+
+            let (packet, client_nonce) = session.encrypt(b"query", MIN_UDP_QUERY_LEN).unwrap();
+            assert_eq!(&packet[..8], b"magicnum");
+            assert_eq!((packet.len() - 8 - 32 - 12 - TAG_LEN) % 64, 0);
+
+            // act as the resolver
+            let client_pk = PublicKey::from(<[u8; 32]>::try_from(&packet[8..40]).unwrap());
+            let mut nonce = [0u8; NONCE_LEN];
+            nonce[..12].copy_from_slice(&packet[40..52]);
+            let (query, response) = match es_version {
+                1 => {
+                    let cipher = SalsaBox::new(&client_pk, &resolver_sk);
+                    let query = cipher.decrypt(&nonce.into(), &packet[52..]).unwrap();
+                    nonce[12..].copy_from_slice(&[1u8; 12]);
+                    (query, cipher.encrypt(&nonce.into(), &b"answer\x80\0\0"[..]))
+                }
+                _ => {
+                    let cipher = ChaChaBox::new(&client_pk, &resolver_sk);
+                    let query = cipher.decrypt(&nonce.into(), &packet[52..]).unwrap();
+                    nonce[12..].copy_from_slice(&[1u8; 12]);
+                    (query, cipher.encrypt(&nonce.into(), &b"answer\x80\0\0"[..]))
+                }
+            };
+            assert_eq!(unpad(query).unwrap(), b"query");
+
+            let mut response_packet = RESOLVER_MAGIC.to_vec();
+            response_packet.extend_from_slice(&nonce);
+            response_packet.extend_from_slice(&response.unwrap());
+            assert_eq!(
+                session.decrypt(&response_packet, &client_nonce).unwrap(),
+                b"answer"
+            );
+            assert!(session.decrypt(&response_packet, &[0u8; 12]).is_err());
+        }
+    }
+
+    /// A resolver answering the certificate query in plain text and every encrypted
+    /// query with `answer`, truncated over UDP when `truncate` is set. With `forge`,
+    /// a datagram which doesn't decrypt comes before each encrypted UDP answer.
+    async fn mock_resolver(truncate: bool, forge: bool) -> (SocketAddr, [u8; 32]) {
+        use hickory_proto::rr::Record;
+        use hickory_proto::rr::rdata::{A, TXT};
+        use tokio::net::{TcpListener, UdpSocket};
+
+        let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let resolver_sk = SecretKey::from([9u8; 32]);
+        let cert = certificate(&signing_key, 2, &resolver_sk.public_key());
+        let answer = move |packet: &[u8], udp: bool| -> Vec<u8> {
+            if &packet[..8] != b"magicnum" {
+                let query = Message::from_vec(packet).unwrap();
+                let mut response = query.clone();
+                response.add_answer(Record::from_rdata(
+                    query.queries()[0].name().clone(),
+                    60,
+                    RData::TXT(TXT::from_bytes(vec![&cert])),
+                ));
+                return response.to_vec().unwrap();
+            }
+            let client_pk = PublicKey::from(<[u8; 32]>::try_from(&packet[8..40]).unwrap());
+            let cipher = ChaChaBox::new(&client_pk, &resolver_sk);
+            let mut nonce = [1u8; NONCE_LEN];
+            nonce[..12].copy_from_slice(&packet[40..52]);
+            let mut query_nonce = [0u8; NONCE_LEN];
+            query_nonce[..12].copy_from_slice(&packet[40..52]);
+            let query = cipher.decrypt(&query_nonce.into(), &packet[52..]).unwrap();
+            let query = Message::from_vec(&unpad(query).unwrap()).unwrap();
+            let mut response = query.clone();
+            if udp && truncate {
+                response.set_truncated(true);
+            } else {
+                response.add_answer(Record::from_rdata(
+                    query.queries()[0].name().clone(),
+                    60,
+                    RData::A(A::new(192, 0, 2, 1)),
+                ));
+            }
+            let mut padded = response.to_vec().unwrap();
+            padded.push(0x80);
+            let mut packet = RESOLVER_MAGIC.to_vec();
+            packet.extend_from_slice(&nonce);
+            packet.extend_from_slice(&cipher.encrypt(&nonce.into(), padded.as_slice()).unwrap());
+            packet
+        };
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(addr).await.unwrap();
+        let udp_answer = answer.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_PACKET_LEN];
+            while let Ok((len, from)) = udp.recv_from(&mut buf).await {
+                if forge && &buf[..8] == b"magicnum" {
+                    let mut forged = RESOLVER_MAGIC.to_vec();
+                    forged.extend_from_slice(&buf[40..52]);
+                    forged.extend_from_slice(&[0u8; 64]);
+                    let _ = udp.send_to(&forged, from).await;
+                }
+                let _ = udp.send_to(&udp_answer(&buf[..len], true), from).await;
+            }
+        });
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = tcp.accept().await {
+                let len = stream.read_u16().await.unwrap();
+                let mut packet = vec![0u8; len as usize];
+                stream.read_exact(&mut packet).await.unwrap();
+                let response = answer(&packet, false);
+                stream.write_u16(response.len() as u16).await.unwrap();
+                stream.write_all(&response).await.unwrap();
+            }
+        });
+        (addr, signing_key.verifying_key().to_bytes())
+    }
+
+    async fn query_mock_resolver(
+        truncate: bool,
+        forge: bool,
+    ) -> (DnsResponse, Arc<DnsCryptServer>) {
+        let (addr, public_key) = mock_resolver(truncate, forge).await;
+        let server =
+            Arc::new(DnsCryptServer::new("2.dnscrypt-cert.example.com", &public_key).unwrap());
+        let connection = DnsCryptConnection::new(
+            server.clone(),
+            ProxyRuntimeProvider::new(None, Default::default(), Default::default()),
+            addr,
+            Duration::from_secs(5),
+        );
+        let mut message = Message::new();
+        message.add_query(Query::query(
+            Name::from_str("example.com.").unwrap(),
+            RecordType::A,
+        ));
+        let response = connection
+            .send(DnsRequest::new(message, Default::default()))
+            .next()
+            .await
+            .unwrap()
+            .unwrap();
+        (response, server)
+    }
+
+    #[tokio::test]
+    async fn dnscrypt_udp_exchange() {
+        let (response, _) = query_mock_resolver(false, false).await;
+        assert_eq!(response.answers()[0].data().to_string(), "192.0.2.1");
+    }
+
+    #[tokio::test]
+    async fn dnscrypt_forged_datagram() {
+        let (response, server) = query_mock_resolver(false, true).await;
+        assert_eq!(response.answers()[0].data().to_string(), "192.0.2.1");
+        // the forged datagram doesn't evict the session
+        assert_eq!(server.sessions.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn dnscrypt_tcp_fallback() {
+        let (response, _) = query_mock_resolver(true, false).await;
+        assert!(!response.truncated());
+        assert_eq!(response.answers()[0].data().to_string(), "192.0.2.1");
+    }
+}
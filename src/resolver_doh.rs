@@ -0,0 +1,368 @@
+use bytes::{Bytes, BytesMut};
+use futures::Future;
+use futures::stream::{self, BoxStream, StreamExt};
+use hickory_proto::ProtoError;
+use hickory_proto::http::{DEFAULT_DNS_QUERY_PATH, Version, request};
+use hickory_proto::op::Message;
+use hickory_proto::xfer::{DnsRequest, DnsResponse};
+use hickory_resolver::config::{NameServerConfig, ResolverOpts};
+use http::header::CONTENT_TYPE;
+use http::{HeaderMap, Request, response};
+use log::{debug, warn};
+use std::sync::Arc;
+
+#[cfg(feature = "dns-over-https")]
+use crate::resolver_runtime_provider::CONNECT_TIMEOUT;
+use crate::resolver_runtime_provider::ProxyRuntimeProvider;
+
+const MIME_APPLICATION_DNS: &str = "application/dns-message";
+
+/// A DNS over HTTPS or HTTP/3 connection which adds custom headers to each request.
+#[derive(Clone)]
+pub struct DohConnection {
+    sender: DohSender,
+    tls_host: Arc<str>,
+    path: Arc<str>,
+    headers: Arc<HeaderMap>,
+}
+
+#[derive(Clone)]
+enum DohSender {
+    #[cfg(feature = "dns-over-https")]
+    H2(h2::client::SendRequest<Bytes>),
+    #[cfg(feature = "dns-over-h3")]
+    H3(h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>),
+}
+
+impl DohConnection {
+    fn new(sender: DohSender, config: &NameServerConfig, headers: Arc<HeaderMap>) -> Self {
+        Self {
+            sender,
+            tls_host: Arc::from(config.tls_dns_name.clone().unwrap_or_default()),
+            path: Arc::from(
+                config
+                    .http_endpoint
+                    .as_deref()
+                    .unwrap_or(DEFAULT_DNS_QUERY_PATH),
+            ),
+            headers,
+        }
+    }
+
+    pub fn send<R: Into<DnsRequest> + Unpin + Send + 'static>(
+        &self,
+        request: R,
+    ) -> BoxStream<'static, Result<DnsResponse, ProtoError>> {
+        let this = self.clone();
+        let request = request.into();
+        stream::once(async move { this.inner_send(request).await }).boxed()
+    }
+
+    async fn inner_send(self, mut request: DnsRequest) -> Result<DnsResponse, ProtoError> {
+        // RFC 8484 recommends a DNS ID of 0 for every request
+        let id = request.id();
+        request.set_id(0);
+        let message = Bytes::from(request.to_vec()?);
+        let (parts, body) = match self.sender.clone() {
+            #[cfg(feature = "dns-over-https")]
+            DohSender::H2(h2) => {
+                let request = self.request(Version::Http2, message.len())?;
+                send_h2(h2, request, message).await?
+            }
+            #[cfg(feature = "dns-over-h3")]
+            DohSender::H3(h3) => {
+                let request = self.request(Version::Http3, message.len())?;
+                send_h3(h3, request, message).await?
+            }
+        };
+        let mut message = decode_response(&parts, &body)?;
+        message.set_id(id);
+        DnsResponse::from_message(message)
+    }
+
+    fn request(&self, version: Version, message_len: usize) -> Result<Request<()>, ProtoError> {
+        let mut request = request::new(version, &self.tls_host, &self.path, message_len)
+            .map_err(|e| ProtoError::from(e.to_string()))?;
+        // custom headers replace the defaults with the same name
+        request
+            .headers_mut()
+            .extend(HeaderMap::clone(&self.headers));
+        Ok(request)
+    }
+}
+
+fn decode_response(parts: &response::Parts, body: &[u8]) -> Result<Message, ProtoError> {
+    if !parts.status.is_success() {
+        return Err(ProtoError::from(format!(
+            "http unsuccessful code: {}, message: {}",
+            parts.status,
+            String::from_utf8_lossy(body)
+        )));
+    }
+    // a missing content type is treated as the standard DNS format
+    if let Some(content_type) = parts.headers.get(CONTENT_TYPE)
+        && content_type != MIME_APPLICATION_DNS
+    {
+        return Err(ProtoError::from(format!(
+            "ContentType unsupported (must be '{MIME_APPLICATION_DNS}'): '{content_type:?}'"
+        )));
+    }
+    Message::from_vec(body)
+}
+
+fn check_body_len(body: &BytesMut) -> Result<(), ProtoError> {
+    if body.len() > u16::MAX as usize {
+        return Err(ProtoError::from(
+            "http response exceeds the DNS message size",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "dns-over-https")]
+async fn send_h2(
+    h2: h2::client::SendRequest<Bytes>,
+    request: Request<()>,
+    message: Bytes,
+) -> Result<(response::Parts, BytesMut), ProtoError> {
+    let mut h2 = h2
+        .ready()
+        .await
+        .map_err(|e| ProtoError::from(format!("h2 send_request error: {e}")))?;
+    let (response, mut send_stream) = h2
+        .send_request(request, false)
+        .map_err(|e| ProtoError::from(format!("h2 send_request error: {e}")))?;
+    send_stream
+        .send_data(message, true)
+        .map_err(|e| ProtoError::from(format!("h2 send_data error: {e}")))?;
+    let (parts, mut body) = response
+        .await
+        .map_err(|e| ProtoError::from(format!("received a stream error: {e}")))?
+        .into_parts();
+    let mut bytes = BytesMut::new();
+    while let Some(data) = body.data().await {
+        let data = data.map_err(|e| ProtoError::from(format!("bad http request: {e}")))?;
+        let _ = body.flow_control().release_capacity(data.len());
+        bytes.extend_from_slice(&data);
+        check_body_len(&bytes)?;
+    }
+    Ok((parts, bytes))
+}
+
+#[cfg(feature = "dns-over-h3")]
+async fn send_h3(
+    mut h3: h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>,
+    request: Request<()>,
+    message: Bytes,
+) -> Result<(response::Parts, BytesMut), ProtoError> {
+    use bytes::BufMut;
+
+    let mut stream = h3
+        .send_request(request)
+        .await
+        .map_err(|e| ProtoError::from(format!("h3 send_request error: {e}")))?;
+    stream
+        .send_data(message)
+        .await
+        .map_err(|e| ProtoError::from(format!("h3 send_data error: {e}")))?;
+    stream
+        .finish()
+        .await
+        .map_err(|e| ProtoError::from(format!("received a stream error: {e}")))?;
+    let (parts, ()) = stream
+        .recv_response()
+        .await
+        .map_err(|e| ProtoError::from(format!("h3 recv_response error: {e}")))?
+        .into_parts();
+    let mut bytes = BytesMut::new();
+    while let Some(data) = stream
+        .recv_data()
+        .await
+        .map_err(|e| ProtoError::from(format!("h3 recv_data error: {e}")))?
+    {
+        bytes.put(data);
+        check_body_len(&bytes)?;
+    }
+    Ok((parts, bytes))
+}
+
+/// Connect to a DNS over HTTPS server through the runtime provider.
+#[cfg(feature = "dns-over-https")]
+pub fn connect_https(
+    runtime_provider: ProxyRuntimeProvider,
+    config: &NameServerConfig,
+    options: &ResolverOpts,
+    headers: Arc<HeaderMap>,
+) -> impl Future<Output = Result<DohConnection, ProtoError>> + Send + 'static {
+    use hickory_proto::runtime::RuntimeProvider;
+    use tokio_rustls::TlsConnector;
+    use tokio_rustls::rustls::pki_types::ServerName;
+
+    let name_server = config.socket_addr;
+    let config = config.clone();
+    let timeout = options.timeout;
+    let mut tls_config = options.tls_config.clone();
+    tls_config.alpn_protocols = vec![b"h2".to_vec()];
+    async move {
+        let tls_host = config.tls_dns_name.clone().unwrap_or_default();
+        let server_name = ServerName::try_from(tls_host.clone())
+            .map_err(|_| ProtoError::from(format!("bad dns_name: {tls_host}")))?;
+        let tcp = runtime_provider
+            .connect_tcp(name_server, config.bind_addr, Some(CONNECT_TIMEOUT))
+            .await?;
+        let tls = tokio::time::timeout(
+            timeout,
+            TlsConnector::from(Arc::new(tls_config)).connect(server_name, tcp.0),
+        )
+        .await
+        .map_err(|_| ProtoError::from(format!("TLS handshake timed out after {timeout:?}")))??;
+        let (send_request, connection) = h2::client::Builder::new()
+            .enable_push(false)
+            .handshake(tls)
+            .await
+            .map_err(|e| ProtoError::from(format!("h2 handshake error: {e}")))?;
+        debug!("h2 connection established to: {}", name_server);
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                warn!("h2 connection failed: {e}");
+            }
+        });
+        Ok(DohConnection::new(
+            DohSender::H2(send_request),
+            &config,
+            headers,
+        ))
+    }
+}
+
+/// Connect to a DNS over HTTP/3 server through the runtime provider.
+#[cfg(feature = "dns-over-h3")]
+pub fn connect_h3(
+    runtime_provider: &ProxyRuntimeProvider,
+    config: &NameServerConfig,
+    options: &ResolverOpts,
+    headers: Arc<HeaderMap>,
+) -> std::io::Result<impl Future<Output = Result<DohConnection, ProtoError>> + Send + 'static> {
+    use futures::future::poll_fn;
+    use hickory_proto::runtime::QuicSocketBinder;
+    use quinn::crypto::rustls::QuicClientConfig;
+    use quinn::{ClientConfig, Endpoint, EndpointConfig, TokioRuntime};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    let name_server = config.socket_addr;
+    let bind_addr = config.bind_addr.unwrap_or(match name_server {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    });
+    let socket = runtime_provider.bind_quic(bind_addr, name_server)?;
+    let config = config.clone();
+    let mut tls_config = options.tls_config.clone();
+    tls_config.alpn_protocols = vec![b"h3".to_vec()];
+    Ok(async move {
+        let tls_host = config.tls_dns_name.clone().unwrap_or_default();
+        let crypto_config = QuicClientConfig::try_from(tls_config)
+            .map_err(|e| ProtoError::from(format!("bad tls config: {e}")))?;
+        let mut endpoint = Endpoint::new_with_abstract_socket(
+            EndpointConfig::default(),
+            None,
+            socket,
+            Arc::new(TokioRuntime),
+        )?;
+        endpoint.set_default_client_config(ClientConfig::new(Arc::new(crypto_config)));
+        let connection = endpoint
+            .connect(name_server, &tls_host)
+            .map_err(|e| ProtoError::from(format!("quic connect error: {e}")))?
+            .await
+            .map_err(|e| ProtoError::from(format!("quic connection failed: {e}")))?;
+        let (mut driver, send_request) = h3::client::new(h3_quinn::Connection::new(connection))
+            .await
+            .map_err(|e| ProtoError::from(format!("h3 connection failed: {e}")))?;
+        debug!("h3 connection established to: {}", name_server);
+        tokio::spawn(async move {
+            if let Err(e) = poll_fn(|cx| driver.poll_close(cx)).await {
+                warn!("h3 connection failed: {e}");
+            }
+        });
+        Ok(DohConnection::new(
+            DohSender::H3(send_request),
+            &config,
+            headers,
+        ))
+    })
+}
+
+#[cfg(all(test, feature = "dns-over-https"))]
+mod tests {
+    use super::*;
+    use hickory_proto::op::{MessageType, Query};
+    use hickory_proto::rr::{Name, RecordType};
+    use hickory_proto::xfer::Protocol;
+    use http::{HeaderValue, Response};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Answers one request, sending back its path and `x-token` header
+    async fn h2_server(listener: TcpListener) -> (String, Option<HeaderValue>) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut connection = h2::server::handshake(stream).await.unwrap();
+        let (request, mut respond) = connection.accept().await.unwrap().unwrap();
+        let seen = (
+            request.uri().path().to_string(),
+            request.headers().get("x-token").cloned(),
+        );
+        let mut body = request.into_body();
+        let mut query = BytesMut::new();
+        while let Some(data) = body.data().await {
+            query.extend_from_slice(&data.unwrap());
+        }
+        let query = Message::from_vec(&query).unwrap();
+        let mut answer = Message::new();
+        answer
+            .set_id(query.id())
+            .set_message_type(MessageType::Response)
+            .add_queries(query.queries().to_vec());
+        let response = Response::builder()
+            .header(CONTENT_TYPE, MIME_APPLICATION_DNS)
+            .body(())
+            .unwrap();
+        let mut send = respond.send_response(response, false).unwrap();
+        send.send_data(Bytes::from(answer.to_vec().unwrap()), true)
+            .unwrap();
+        tokio::spawn(async move { while connection.accept().await.is_some() {} });
+        seen
+    }
+
+    #[tokio::test]
+    async fn custom_path_and_headers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(h2_server(listener));
+
+        let (send_request, connection) =
+            h2::client::handshake(TcpStream::connect(address).await.unwrap())
+                .await
+                .unwrap();
+        tokio::spawn(connection);
+        let mut config = NameServerConfig::new(address, Protocol::Https);
+        config.tls_dns_name = Some("dns.example".to_string());
+        config.http_endpoint = Some("/custom-query".to_string());
+        let mut headers = HeaderMap::new();
+        headers.insert("x-token", HeaderValue::from_static("secret"));
+        let conn = DohConnection::new(DohSender::H2(send_request), &config, Arc::new(headers));
+
+        let mut query = Message::new();
+        query.set_id(1234).add_query(Query::query(
+            Name::from_ascii("example.com.").unwrap(),
+            RecordType::A,
+        ));
+        let response = conn
+            .send(DnsRequest::new(query, Default::default()))
+            .next()
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.id(), 1234);
+        let (path, token) = server.await.unwrap();
+        assert_eq!(path, "/custom-query");
+        assert_eq!(token.unwrap(), "secret");
+    }
+}
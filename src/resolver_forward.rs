@@ -0,0 +1,231 @@
+use futures::stream::{self, BoxStream, StreamExt};
+use hickory_proto::ProtoError;
+#[cfg(feature = "dnssec")]
+use hickory_proto::dnssec::DnssecDnsHandle;
+use hickory_proto::op::Message;
+use hickory_proto::xfer::{DnsHandle, DnsRequest, DnsResponse, FirstAnswer, Protocol};
+use hickory_resolver::config::{NameServerConfig, ResolverConfig, ResolverOpts};
+use hickory_resolver::name_server::ConnectionProvider;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::time::timeout;
+
+use crate::resolver_runtime_provider::{ProxyConnection, ProxyConnectionProvider};
+
+/// Sends whole messages to the name servers of an upstream and returns their
+/// responses as they are, unlike hickory's name server pool which turns negative
+/// responses into errors.
+#[derive(Clone)]
+pub struct Forwarder {
+    name_servers: Arc<[NameServerConfig]>,
+    options: ResolverOpts,
+    provider: ProxyConnectionProvider,
+    connections: Arc<Mutex<HashMap<(SocketAddr, Protocol), ProxyConnection>>>,
+}
+
+impl Forwarder {
+    pub fn new(
+        config: &ResolverConfig,
+        options: ResolverOpts,
+        provider: ProxyConnectionProvider,
+    ) -> Self {
+        Self {
+            name_servers: config.name_servers().into(),
+            options,
+            provider,
+            connections: Arc::default(),
+        }
+    }
+
+    /// Send `message` to each name server until one answers, validating the response
+    /// with DNSSEC unless the client disabled checking.
+    pub async fn forward(&self, message: &Message) -> Result<DnsResponse, ProtoError> {
+        #[cfg(feature = "dnssec")]
+        if self.options.validate && !message.checking_disabled() {
+            let request = DnsRequest::new(message.clone(), Default::default());
+            return DnssecDnsHandle::new(self.clone())
+                .send(request)
+                .first_answer()
+                .await;
+        }
+        self.exchange(message).await
+    }
+
+    /// Send `message` to each name server until one answers. A truncated UDP
+    /// response is asked again over TCP.
+    async fn exchange(&self, message: &Message) -> Result<DnsResponse, ProtoError> {
+        let mut last_error = ProtoError::from("no name server");
+        for config in self.name_servers.iter() {
+            match self.send(config, message).await {
+                Ok(response) if response.truncated() && config.protocol == Protocol::Udp => {
+                    let mut tcp = config.clone();
+                    tcp.protocol = Protocol::Tcp;
+                    return Ok(self.send(&tcp, message).await.unwrap_or(response));
+                }
+                Ok(response) => return Ok(response),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn send(
+        &self,
+        config: &NameServerConfig,
+        message: &Message,
+    ) -> Result<DnsResponse, ProtoError> {
+        let key = (config.socket_addr, config.protocol);
+        let cached = self.connections.lock().unwrap().get(&key).cloned();
+        let connection = match cached {
+            Some(connection) => connection,
+            None => {
+                let connection = self.provider.new_connection(config, &self.options)?.await?;
+                self.connections
+                    .lock()
+                    .unwrap()
+                    .insert(key, connection.clone());
+                connection
+            }
+        };
+        let request = DnsRequest::new(message.clone(), Default::default());
+        let result = match timeout(
+            self.options.timeout,
+            connection.send(request).first_answer(),
+        )
+        .await
+        {
+            Ok(result) => result,
+            Err(_) => Err(ProtoError::from(format!(
+                "{} timed out after {:?}",
+                config.socket_addr, self.options.timeout
+            ))),
+        };
+        // the next message gets a new connection
+        if result.is_err() {
+            self.connections.lock().unwrap().remove(&key);
+        }
+        result
+    }
+}
+
+/// Lets hickory's DNSSEC handle validate the forwarded responses
+impl DnsHandle for Forwarder {
+    type Response = BoxStream<'static, Result<DnsResponse, ProtoError>>;
+
+    fn send<R: Into<DnsRequest> + Unpin + Send + 'static>(&self, request: R) -> Self::Response {
+        let forwarder = self.clone();
+        let request = request.into();
+        stream::once(async move { forwarder.exchange(&request).await }).boxed()
+    }
+}
+
+impl Debug for Forwarder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Forwarder")
+            .field("name_servers", &self.name_servers)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Upstream;
+    use crate::resolver::{RecursiveResolver, UpstreamResolver};
+    use hickory_proto::op::{MessageType, Query, ResponseCode};
+    use hickory_proto::rr::rdata::{A, SOA};
+    use hickory_proto::rr::{Name, RData, Record, RecordType};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn response_to(request: &Message, truncated: bool) -> Message {
+        let mut response = Message::new();
+        response
+            .set_id(request.id())
+            .set_message_type(MessageType::Response)
+            .set_checking_disabled(request.checking_disabled())
+            .add_queries(request.queries().to_vec());
+        let name = request.queries()[0].name().clone();
+        if truncated {
+            response.set_truncated(true);
+        } else if name.to_ascii().starts_with("missing") {
+            let soa = SOA::new(name.clone(), name.clone(), 1, 60, 60, 60, 60);
+            response
+                .set_response_code(ResponseCode::NXDomain)
+                .add_name_server(Record::from_rdata(name, 60, RData::SOA(soa)));
+        } else {
+            response.add_answer(Record::from_rdata(name, 60, RData::A(A::new(192, 0, 2, 1))));
+        }
+        response
+    }
+
+    /// A name server whose UDP responses to `big.` names are truncated, and which
+    /// answers them over TCP.
+    async fn name_server() -> SocketAddr {
+        let udp = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = udp.local_addr().unwrap();
+        let tcp = tokio::net::TcpListener::bind(addr).await.unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (len, peer) = udp.recv_from(&mut buf).await.unwrap();
+                let request = Message::from_vec(&buf[..len]).unwrap();
+                let truncated = request.queries()[0].name().to_ascii().starts_with("big");
+                let response = response_to(&request, truncated).to_vec().unwrap();
+                udp.send_to(&response, peer).await.unwrap();
+            }
+        });
+        tokio::spawn(async move {
+            let (mut stream, _) = tcp.accept().await.unwrap();
+            let len = stream.read_u16().await.unwrap();
+            let mut buf = vec![0u8; usize::from(len)];
+            stream.read_exact(&mut buf).await.unwrap();
+            let request = Message::from_vec(&buf).unwrap();
+            let response = response_to(&request, false).to_vec().unwrap();
+            stream.write_u16(response.len() as u16).await.unwrap();
+            stream.write_all(&response).await.unwrap();
+        });
+        addr
+    }
+
+    fn query(name: &str) -> Message {
+        let mut message = Message::new();
+        message
+            .set_id(1234)
+            .set_recursion_desired(true)
+            .set_checking_disabled(true)
+            .add_query(Query::query(Name::from_ascii(name).unwrap(), RecordType::A));
+        message
+    }
+
+    #[tokio::test]
+    async fn forward_raw_messages() {
+        let resolver: RecursiveResolver = (
+            &Upstream::UdpUpstream {
+                address: vec![name_server().await],
+                proxy: None,
+                bind: Default::default(),
+                ecs: Default::default(),
+                dnssec: None,
+            },
+            None,
+        )
+            .into();
+
+        let response = resolver
+            .forward(&query("missing.example."), None)
+            .await
+            .unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+        assert!(response.checking_disabled());
+        assert_eq!(response.name_servers()[0].record_type(), RecordType::SOA);
+
+        let response = resolver
+            .forward(&query("big.example."), None)
+            .await
+            .unwrap();
+        assert!(!response.truncated());
+        assert_eq!(response.answers().len(), 1);
+    }
+}
@@ -1,42 +1,84 @@
-use futures::Future;
+use futures::stream::BoxStream;
+use futures::{Future, FutureExt, StreamExt, TryFutureExt, TryStreamExt};
+use hickory_proto::ProtoError;
 #[cfg(any(feature = "dns-over-h3", feature = "dns-over-quic"))]
 use hickory_proto::runtime::QuicSocketBinder;
 use hickory_proto::runtime::{
     RuntimeProvider, TokioHandle, TokioTime, iocompat::AsyncIoTokioAsStd,
 };
-use hickory_resolver::name_server::GenericConnector;
+use hickory_proto::xfer::{DnsHandle, DnsRequest, DnsResponse, Protocol};
+use hickory_resolver::config::{NameServerConfig, ResolverOpts};
+use hickory_resolver::name_server::{ConnectionProvider, GenericConnection, GenericConnector};
+#[cfg(any(feature = "dns-over-https", feature = "dns-over-h3"))]
+use http::HeaderMap;
+#[cfg(any(
+    feature = "dns-over-tls",
+    feature = "dns-over-https",
+    feature = "dns-over-h3",
+    feature = "dns-over-quic"
+))]
+use rustls::ClientConfig;
 use std::io;
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
+#[cfg(any(feature = "dns-over-h3", feature = "dns-over-quic"))]
+use tokio::runtime::{Handle, RuntimeFlavor};
 use tokio::time::timeout;
 
+use crate::dnstap::Tap;
+use crate::ecs;
+#[cfg(feature = "dnscrypt")]
+use crate::resolver_dnscrypt::{DnsCryptConnection, DnsCryptServer};
+#[cfg(any(feature = "dns-over-https", feature = "dns-over-h3"))]
+use crate::resolver_doh::{self, DohConnection};
 use crate::resolver_proxy;
-use crate::resolver_proxy::{ProxyConfig, Socks5UdpSocket};
+use crate::resolver_proxy::{BindConfig, Proxy, ProxyHostResolver, ProxyUdpSocket};
+#[cfg(any(
+    feature = "dns-over-tls",
+    feature = "dns-over-https",
+    feature = "dns-over-h3",
+    feature = "dns-over-quic"
+))]
+use crate::resolver_tls;
+use crate::sections;
 
-const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+pub(crate) const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// The Tokio Runtime for async execution
 #[derive(Clone)]
 pub struct ProxyRuntimeProvider {
-    proxy: Option<Arc<ProxyConfig>>,
+    proxy: Option<Arc<Proxy>>,
+    bind: Arc<BindConfig>,
+    proxy_hosts: Arc<ProxyHostResolver>,
     handle: TokioHandle,
 }
 
 impl ProxyRuntimeProvider {
-    pub fn new(proxy: Option<ProxyConfig>) -> Self {
+    pub fn new(
+        proxy: Option<Proxy>,
+        bind: BindConfig,
+        proxy_hosts: Arc<ProxyHostResolver>,
+    ) -> Self {
         Self {
             proxy: proxy.map(Arc::new),
+            bind: Arc::new(bind),
+            proxy_hosts,
             handle: TokioHandle::default(),
         }
     }
+
+    /// Whether UDP sockets can be bound, through the proxy if any
+    pub fn supports_udp(&self) -> bool {
+        self.proxy.as_deref().is_none_or(Proxy::supports_udp)
+    }
 }
 
 impl RuntimeProvider for ProxyRuntimeProvider {
     type Handle = TokioHandle;
     type Timer = TokioTime;
-    type Udp = Socks5UdpSocket;
+    type Udp = ProxyUdpSocket;
     type Tcp = AsyncIoTokioAsStd<resolver_proxy::TcpStream>;
 
     fn create_handle(&self) -> Self::Handle {
@@ -50,9 +92,16 @@ impl RuntimeProvider for ProxyRuntimeProvider {
         wait_for: Option<Duration>,
     ) -> Pin<Box<dyn Send + Future<Output = io::Result<Self::Tcp>>>> {
         let proxy_config = self.proxy.clone();
+        let bind = self.bind.clone();
+        let proxy_hosts = self.proxy_hosts.clone();
         Box::pin(async move {
-            let future =
-                resolver_proxy::connect_tcp(server_addr, bind_addr, proxy_config.as_deref());
+            let future = resolver_proxy::connect_tcp(
+                server_addr,
+                bind_addr,
+                proxy_config.as_deref(),
+                &bind,
+                &proxy_hosts,
+            );
             let wait_for = wait_for.unwrap_or(CONNECT_TIMEOUT);
             match timeout(wait_for, future).await {
                 Ok(Ok(socket)) => Ok(AsyncIoTokioAsStd(socket)),
@@ -71,8 +120,16 @@ impl RuntimeProvider for ProxyRuntimeProvider {
         server_addr: SocketAddr,
     ) -> Pin<Box<dyn Send + Future<Output = io::Result<Self::Udp>>>> {
         let proxy_config = self.proxy.clone();
+        let bind = self.bind.clone();
+        let proxy_hosts = self.proxy_hosts.clone();
         Box::pin(async move {
-            let future = resolver_proxy::bind_udp(local_addr, server_addr, proxy_config.as_deref());
+            let future = resolver_proxy::bind_udp(
+                local_addr,
+                server_addr,
+                proxy_config.as_deref(),
+                &bind,
+                &proxy_hosts,
+            );
             let wait_for = CONNECT_TIMEOUT;
             match timeout(wait_for, future).await {
                 Ok(Ok(socket)) => Ok(socket),
@@ -91,6 +148,10 @@ impl RuntimeProvider for ProxyRuntimeProvider {
     }
 }
 
+/// quinn binds its sockets synchronously. Without a proxy nothing is waited for,
+/// through one the relay is set up, and the proxy host resolved, by blocking the
+/// worker thread. This needs a multi-thread Tokio runtime, a current-thread one
+/// couldn't drive them and QUIC through a proxy fails there.
 #[cfg(any(feature = "dns-over-h3", feature = "dns-over-quic"))]
 impl QuicSocketBinder for ProxyRuntimeProvider {
     fn bind_quic(
@@ -98,9 +159,14 @@ impl QuicSocketBinder for ProxyRuntimeProvider {
         local_addr: SocketAddr,
         server_addr: SocketAddr,
     ) -> Result<std::sync::Arc<dyn quinn::AsyncUdpSocket>, io::Error> {
-        let socket = futures::executor::block_on(async {
-            let future =
-                resolver_proxy::quic_binder(local_addr, server_addr, self.proxy.as_deref());
+        let bind = async {
+            let future = resolver_proxy::quic_binder(
+                local_addr,
+                server_addr,
+                self.proxy.as_deref(),
+                &self.bind,
+                &self.proxy_hosts,
+            );
             let wait_for = CONNECT_TIMEOUT;
             match timeout(wait_for, future).await {
                 Ok(Ok(socket)) => Ok(socket),
@@ -110,9 +176,272 @@ impl QuicSocketBinder for ProxyRuntimeProvider {
                     format!("bind {local_addr:?} timed out after {wait_for:?}"),
                 )),
             }
-        })?;
-        Ok(socket)
+        };
+        if self.proxy.is_none() {
+            return bind
+                .now_or_never()
+                .unwrap_or_else(|| Err(io::Error::other("binding the QUIC socket blocked")));
+        }
+        match Handle::try_current() {
+            Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+                tokio::task::block_in_place(|| handle.block_on(bind))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "QUIC through a proxy needs a multi-thread Tokio runtime",
+            )),
+        }
+    }
+}
+
+/// Creates connections through a `ProxyRuntimeProvider`.
+///
+/// DNSCrypt upstreams and DNS over HTTPS and HTTP/3 upstreams with custom request
+/// headers use our own clients, everything else is delegated to hickory's
+/// `GenericConnector`.
+#[derive(Clone)]
+pub struct ProxyConnectionProvider {
+    connector: GenericConnector<ProxyRuntimeProvider>,
+    #[cfg(any(
+        feature = "dns-over-https",
+        feature = "dns-over-h3",
+        feature = "dnscrypt"
+    ))]
+    runtime_provider: ProxyRuntimeProvider,
+    #[cfg(any(feature = "dns-over-https", feature = "dns-over-h3"))]
+    http_headers: Option<Arc<HeaderMap>>,
+    #[cfg(any(
+        feature = "dns-over-tls",
+        feature = "dns-over-https",
+        feature = "dns-over-h3",
+        feature = "dns-over-quic"
+    ))]
+    tls_config: Option<Arc<ClientConfig>>,
+    #[cfg(feature = "dnscrypt")]
+    dnscrypt: Option<Arc<DnsCryptServer>>,
+}
+
+impl ProxyConnectionProvider {
+    pub fn new(runtime_provider: ProxyRuntimeProvider) -> Self {
+        Self {
+            #[cfg(any(
+                feature = "dns-over-https",
+                feature = "dns-over-h3",
+                feature = "dnscrypt"
+            ))]
+            runtime_provider: runtime_provider.clone(),
+            connector: GenericConnector::new(runtime_provider),
+            #[cfg(any(feature = "dns-over-https", feature = "dns-over-h3"))]
+            http_headers: None,
+            #[cfg(any(
+                feature = "dns-over-tls",
+                feature = "dns-over-https",
+                feature = "dns-over-h3",
+                feature = "dns-over-quic"
+            ))]
+            tls_config: None,
+            #[cfg(feature = "dnscrypt")]
+            dnscrypt: None,
+        }
+    }
+
+    /// Send every query to the name servers with DNSCrypt.
+    #[cfg(feature = "dnscrypt")]
+    pub fn with_dnscrypt(mut self, server: Arc<DnsCryptServer>) -> Self {
+        self.dnscrypt = Some(server);
+        self
+    }
+
+    /// Headers added to every DNS over HTTPS or HTTP/3 request.
+    #[cfg(any(feature = "dns-over-https", feature = "dns-over-h3"))]
+    pub fn with_http_headers(mut self, headers: HeaderMap) -> Self {
+        self.http_headers = Some(Arc::new(headers));
+        self
+    }
+
+    /// Require one of the server certificates to match a pinned hash.
+    #[cfg(any(
+        feature = "dns-over-tls",
+        feature = "dns-over-https",
+        feature = "dns-over-h3",
+        feature = "dns-over-quic"
+    ))]
+    pub fn with_cert_hashes(mut self, hashes: Vec<[u8; 32]>) -> Self {
+        self.tls_config = Some(Arc::new(resolver_tls::pinned_client_config(hashes)));
+        self
+    }
+}
+
+impl ConnectionProvider for ProxyConnectionProvider {
+    type Conn = ProxyConnection;
+    type FutureConn = Pin<Box<dyn Send + Future<Output = Result<Self::Conn, ProtoError>>>>;
+    type RuntimeProvider = ProxyRuntimeProvider;
+
+    fn new_connection(
+        &self,
+        config: &NameServerConfig,
+        options: &ResolverOpts,
+    ) -> Result<Self::FutureConn, io::Error> {
+        let (name_server, protocol) = (config.socket_addr, config.protocol);
+        let wrap = move |conn| ProxyConnection {
+            conn,
+            name_server,
+            protocol,
+        };
+        #[cfg(feature = "dnscrypt")]
+        if let Some(server) = self.dnscrypt.clone() {
+            let conn = DnsCryptConnection::new(
+                server,
+                self.runtime_provider.clone(),
+                config.socket_addr,
+                options.timeout,
+            );
+            return Ok(futures::future::ok(wrap(Connection::DnsCrypt(conn))).boxed());
+        }
+        #[cfg(any(
+            feature = "dns-over-tls",
+            feature = "dns-over-https",
+            feature = "dns-over-h3",
+            feature = "dns-over-quic"
+        ))]
+        let pinned_options;
+        #[cfg(any(
+            feature = "dns-over-tls",
+            feature = "dns-over-https",
+            feature = "dns-over-h3",
+            feature = "dns-over-quic"
+        ))]
+        let options = match &self.tls_config {
+            Some(tls_config) => {
+                let mut opts = options.clone();
+                opts.tls_config = ClientConfig::clone(tls_config);
+                pinned_options = opts;
+                &pinned_options
+            }
+            None => options,
+        };
+        #[cfg(any(feature = "dns-over-https", feature = "dns-over-h3"))]
+        if let Some(headers) = self.http_headers.clone() {
+            match config.protocol {
+                #[cfg(feature = "dns-over-https")]
+                Protocol::Https => {
+                    let connect = resolver_doh::connect_https(
+                        self.runtime_provider.clone(),
+                        config,
+                        options,
+                        headers,
+                    );
+                    return Ok(connect
+                        .map_ok(move |conn| wrap(Connection::Doh(conn)))
+                        .boxed());
+                }
+                #[cfg(feature = "dns-over-h3")]
+                Protocol::H3 => {
+                    let connect =
+                        resolver_doh::connect_h3(&self.runtime_provider, config, options, headers)?;
+                    return Ok(connect
+                        .map_ok(move |conn| wrap(Connection::Doh(conn)))
+                        .boxed());
+                }
+                _ => (),
+            }
+        }
+        let connect = self.connector.new_connection(config, options)?;
+        Ok(connect
+            .map_ok(move |conn| wrap(Connection::Generic(conn)))
+            .boxed())
+    }
+}
+
+/// A connection created by `ProxyConnectionProvider` to the name server `name_server`
+#[derive(Clone)]
+pub struct ProxyConnection {
+    conn: Connection,
+    name_server: SocketAddr,
+    protocol: Protocol,
+}
+
+#[derive(Clone)]
+enum Connection {
+    Generic(GenericConnection),
+    #[cfg(any(feature = "dns-over-https", feature = "dns-over-h3"))]
+    Doh(DohConnection),
+    #[cfg(feature = "dnscrypt")]
+    DnsCrypt(DnsCryptConnection),
+}
+
+impl DnsHandle for ProxyConnection {
+    type Response = BoxStream<'static, Result<DnsResponse, ProtoError>>;
+
+    fn send<R: Into<DnsRequest> + Unpin + Send + 'static>(&self, request: R) -> Self::Response {
+        let mut request = request.into();
+        ecs::apply(&mut request);
+        let recorder = sections::recorder(&request);
+        let tap = Tap::current().map(|tap| (tap, request.clone(), SystemTime::now()));
+        let mut response = match &self.conn {
+            Connection::Generic(conn) => conn.send(request).boxed(),
+            #[cfg(any(feature = "dns-over-https", feature = "dns-over-h3"))]
+            Connection::Doh(conn) => conn.send(request),
+            #[cfg(feature = "dnscrypt")]
+            Connection::DnsCrypt(conn) => conn.send(request),
+        };
+        if let Some(recorder) = recorder {
+            response = response
+                .inspect_ok(move |response| recorder.record(response))
+                .boxed();
+        }
+        if let Some((tap, query, query_time)) = tap {
+            let (name_server, protocol) = (self.name_server, self.protocol);
+            response = response
+                .inspect(move |response| {
+                    let response = response.as_ref().ok();
+                    tap.exchanged(name_server, protocol, query_time, &query, response);
+                })
+                .boxed();
+        }
+        response
     }
 }
 
-pub type ProxyConnectionProvider = GenericConnector<ProxyRuntimeProvider>;
+#[cfg(all(test, any(feature = "dns-over-h3", feature = "dns-over-quic")))]
+mod tests {
+    use super::*;
+    use crate::resolver_proxy::ProxyMode;
+
+    fn provider(proxy: Option<&str>) -> ProxyRuntimeProvider {
+        let proxy = proxy.map(|url| Proxy {
+            servers: vec![url.parse().unwrap()],
+            mode: ProxyMode::Fallback,
+        });
+        ProxyRuntimeProvider::new(proxy, BindConfig::default(), Arc::default())
+    }
+
+    #[tokio::test]
+    async fn bind_quic_current_thread() {
+        let local_addr = "127.0.0.1:0".parse().unwrap();
+        let server_addr = "127.0.0.1:853".parse().unwrap();
+        assert!(provider(None).bind_quic(local_addr, server_addr).is_ok());
+        // the proxy could not be reached without blocking the only thread
+        let error = provider(Some("socks5://127.0.0.1:1080"))
+            .bind_quic(local_addr, server_addr)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn bind_quic_multi_thread() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = format!("socks5://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let error = provider(Some(&proxy))
+            .bind_quic(
+                "127.0.0.1:0".parse().unwrap(),
+                "127.0.0.1:853".parse().unwrap(),
+            )
+            .err()
+            .unwrap();
+        // the proxy was tried rather than refused
+        assert_ne!(error.kind(), io::ErrorKind::Unsupported);
+    }
+}
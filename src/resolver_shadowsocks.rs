@@ -0,0 +1,184 @@
+use bytes::BytesMut;
+use fast_socks5::util::target_addr::TargetAddr;
+use shadowsocks::config::{ServerAddr, ServerConfig, ServerType};
+use shadowsocks::context::{Context, SharedContext};
+use shadowsocks::crypto::CipherKind;
+use shadowsocks::relay::Address;
+use shadowsocks::relay::tcprelay::ProxyClientStream;
+use shadowsocks::relay::udprelay::crypto_io::{decrypt_server_payload, encrypt_client_payload};
+use shadowsocks::relay::udprelay::options::UdpSocketControlData;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::resolver_proxy::{
+    ProxyConfig, ProxyParseError, ProxyProtocol, ProxyServer, ProxyStream,
+};
+
+static CONTEXT: LazyLock<SharedContext> = LazyLock::new(|| Context::new_shared(ServerType::Local));
+
+/// Parse a SIP002 `ss://` url, keeping the host name of the server.
+pub fn parse_url(s: &str) -> Result<ProxyConfig, ProxyParseError> {
+    let config =
+        ServerConfig::from_url(s).map_err(|e| ProxyParseError::Shadowsocks(e.to_string()))?;
+    let method = config.method();
+    if !method.is_aead() && !method.is_aead_2022() {
+        return Err(ProxyParseError::Shadowsocks(format!(
+            "unsupported cipher {method}"
+        )));
+    }
+    if config.plugin().is_some() {
+        return Err(ProxyParseError::Shadowsocks(
+            "plugins are not supported".to_string(),
+        ));
+    }
+    let server = match config.addr() {
+        ServerAddr::SocketAddr(addr) => ProxyServer::Addr(*addr),
+        ServerAddr::DomainName(host, port) => ProxyServer::Host(host.clone(), *port),
+    };
+    Ok(ProxyConfig {
+        proto: ProxyProtocol::Shadowsocks,
+        server,
+        username: Some(method.to_string()),
+        password: Some(config.export_password()),
+    })
+}
+
+pub(crate) fn server_config(proxy: &ProxyConfig) -> io::Result<ServerConfig> {
+    let method = proxy.username.as_deref().unwrap_or_default();
+    let method = method.parse::<CipherKind>().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unsupported cipher {method}"),
+        )
+    })?;
+    let addr = match &proxy.server {
+        ProxyServer::Addr(addr) => ServerAddr::SocketAddr(*addr),
+        ProxyServer::Host(host, port) => ServerAddr::DomainName(host.clone(), *port),
+    };
+    let password = proxy.password.clone().unwrap_or_default();
+    ServerConfig::new(addr, password, method)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Ask the shadowsocks `proxy`, reached over `stream`, to relay to `target`.
+pub fn tunnel(
+    stream: ProxyStream,
+    proxy: &ProxyConfig,
+    target: TargetAddr,
+) -> io::Result<ProxyStream> {
+    let config = server_config(proxy)?;
+    let target = match target {
+        TargetAddr::Ip(addr) => Address::SocketAddress(addr),
+        TargetAddr::Domain(host, port) => Address::DomainNameAddress(host, port),
+    };
+    Ok(Box::new(ProxyClientStream::from_stream(
+        CONTEXT.clone(),
+        stream,
+        &config,
+        target,
+    )))
+}
+
+/// Encrypts the datagrams of one UDP session with a shadowsocks server.
+pub struct UdpRelay {
+    config: ServerConfig,
+    session_id: u64,
+    packet_id: AtomicU64,
+}
+
+impl UdpRelay {
+    pub fn new(proxy: &ProxyConfig) -> io::Result<Self> {
+        Ok(Self {
+            config: server_config(proxy)?,
+            session_id: rand::random(),
+            packet_id: AtomicU64::new(0),
+        })
+    }
+
+    pub fn encode(&self, target: SocketAddr, payload: &[u8]) -> Vec<u8> {
+        // the session and packet ids are only sent by 2022 ciphers
+        let mut control = UdpSocketControlData::default();
+        control.client_session_id = self.session_id;
+        control.packet_id = self.packet_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut packet = BytesMut::with_capacity(payload.len() + 64);
+        encrypt_client_payload(
+            &CONTEXT,
+            self.config.method(),
+            self.config.key(),
+            &Address::SocketAddress(target),
+            &control,
+            self.config.identity_keys(),
+            payload,
+            &mut packet,
+        );
+        packet.to_vec()
+    }
+
+    /// Decrypt `packet` in place, the payload is moved to its start.
+    pub fn decode(&self, packet: &mut [u8]) -> io::Result<(SocketAddr, Range<usize>)> {
+        let (len, addr, _) =
+            decrypt_server_payload(&CONTEXT, self.config.method(), self.config.key(), packet)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        match addr {
+            Address::SocketAddress(addr) => Ok((addr, 0..len)),
+            Address::DomainNameAddress(host, _) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected datagram from {host}"),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ss_urls() {
+        let proxy = parse_url("ss://aes-128-gcm:secret@127.0.0.1:8388").unwrap();
+        assert_eq!(proxy.proto, ProxyProtocol::Shadowsocks);
+        assert_eq!(
+            proxy.server,
+            ProxyServer::Addr("127.0.0.1:8388".parse().unwrap())
+        );
+        assert_eq!(proxy.username.as_deref(), Some("aes-128-gcm"));
+        assert_eq!(proxy.password.as_deref(), Some("secret"));
+
+        // base64url("aes-256-gcm:pass")
+        let proxy = parse_url("ss://YWVzLTI1Ni1nY206cGFzcw@proxy.lan:8388#name").unwrap();
+        assert_eq!(
+            proxy.server,
+            ProxyServer::Host("proxy.lan".to_string(), 8388)
+        );
+        assert_eq!(proxy.username.as_deref(), Some("aes-256-gcm"));
+
+        let proxy =
+            parse_url("ss://2022-blake3-aes-128-gcm:AAECAwQFBgcICQoLDA0ODw==@[::1]:8388").unwrap();
+        assert_eq!(
+            proxy.server,
+            ProxyServer::Addr("[::1]:8388".parse().unwrap())
+        );
+        assert!(server_config(&proxy).is_ok());
+
+        // 2022 keys must have the length of the cipher key
+        assert!(parse_url("ss://2022-blake3-aes-128-gcm:c2hvcnQ=@127.0.0.1:8388").is_err());
+        assert!(parse_url("ss://none:x@127.0.0.1:8388").is_err());
+        assert!(parse_url("ss://aes-128-gcm:x@127.0.0.1:8388/?plugin=obfs-local").is_err());
+    }
+
+    #[test]
+    fn udp_relay_round_trip() {
+        let proxy = parse_url("ss://chacha20-ietf-poly1305:secret@127.0.0.1:8388").unwrap();
+        let relay = UdpRelay::new(&proxy).unwrap();
+        let target = "192.0.2.1:53".parse().unwrap();
+        let mut packet = relay.encode(target, b"query");
+        assert!(relay.decode(&mut packet[..4]).is_err());
+        // the client decodes what a server would send back, which has the same format
+        let (addr, data) = relay.decode(&mut packet).unwrap();
+        assert_eq!(addr, target);
+        assert_eq!(&packet[data], b"query");
+    }
+}
@@ -0,0 +1,161 @@
+use hickory_proto::xfer::Protocol;
+use hickory_resolver::config::{NameServerConfig, ResolverConfig};
+use log::{info, warn};
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The name servers of a resolv.conf file.
+pub fn parse_resolv_conf(contents: &str) -> Vec<SocketAddr> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            match fields.next()? {
+                "nameserver" => parse_name_server(fields.next()?),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Parse `ip` or `ipv6%zone`, where the zone is an interface name or index.
+fn parse_name_server(addr: &str) -> Option<SocketAddr> {
+    let (ip, zone) = match addr.split_once('%') {
+        Some((ip, zone)) => (ip, Some(zone)),
+        None => (addr, None),
+    };
+    match ip.parse::<IpAddr>().ok()? {
+        IpAddr::V6(ip) => {
+            let scope_id = match zone {
+                Some(zone) => zone.parse().ok().or_else(|| if_nametoindex(zone))?,
+                None => 0,
+            };
+            Some(SocketAddrV6::new(ip, 53, 0, scope_id).into())
+        }
+        ip => Some(SocketAddr::new(ip, 53)),
+    }
+}
+
+#[cfg(unix)]
+fn if_nametoindex(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => None,
+        index => Some(index),
+    }
+}
+
+#[cfg(not(unix))]
+fn if_nametoindex(_name: &str) -> Option<u32> {
+    None
+}
+
+/// Read the name servers of `path`, an unreadable file has none.
+pub fn read_resolv_conf(path: &Path) -> Vec<SocketAddr> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => parse_resolv_conf(&contents),
+        Err(e) => {
+            warn!("Failed to read {}: {}", path.display(), e);
+            Vec::new()
+        }
+    }
+}
+
+/// Query each name server over UDP, with TCP for truncated responses, or only over
+/// TCP without `udp`.
+pub fn resolver_config(name_servers: &[SocketAddr], udp: bool) -> ResolverConfig {
+    let mut resolver_config = ResolverConfig::new();
+    for addr in name_servers {
+        if udp {
+            resolver_config.add_name_server(NameServerConfig::new(*addr, Protocol::Udp));
+        }
+        resolver_config.add_name_server(NameServerConfig::new(*addr, Protocol::Tcp));
+    }
+    resolver_config
+}
+
+/// Poll `path` and pass its name servers to `update` whenever they change,
+/// until `update` returns false.
+pub async fn watch_resolv_conf<F>(path: PathBuf, mut name_servers: Vec<SocketAddr>, update: F)
+where
+    F: Fn(&[SocketAddr]) -> bool,
+{
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last_modified: Option<SystemTime> = modified(&path);
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        let modified = modified(&path);
+        if modified == last_modified {
+            continue;
+        }
+        last_modified = modified;
+        let new_name_servers = read_resolv_conf(&path);
+        if new_name_servers != name_servers {
+            info!(
+                "Name servers in {} changed to {:?}",
+                path.display(),
+                new_name_servers
+            );
+            if !update(&new_name_servers) {
+                return;
+            }
+            name_servers = new_name_servers;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_name_servers() {
+        let name_servers = parse_resolv_conf(
+            "# Generated by NetworkManager
+search lan
+nameserver 192.168.1.1
+nameserver 2001:db8::53 # secondary
+;nameserver 10.0.0.1
+nameserver fe80::1%2
+nameserver not-an-ip
+options edns0",
+        );
+        assert_eq!(
+            name_servers,
+            vec![
+                "192.168.1.1:53".parse().unwrap(),
+                "[2001:db8::53]:53".parse().unwrap(),
+                "[fe80::1%2]:53".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn watch_changes() {
+        let path = std::env::temp_dir().join(format!("yadns-resolv-{}.conf", std::process::id()));
+        std::fs::write(&path, "nameserver 192.0.2.1\n").unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let watch = tokio::spawn(watch_resolv_conf(
+            path.clone(),
+            read_resolv_conf(&path),
+            move |name_servers| tx.send(name_servers.to_vec()).is_ok(),
+        ));
+        tokio::time::sleep(POLL_INTERVAL).await;
+        // make sure the modification time changes on coarse file systems
+        std::fs::write(&path, "nameserver 192.0.2.2\n").unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        tokio::time::sleep(POLL_INTERVAL * 2).await;
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            vec!["192.0.2.2:53".parse::<SocketAddr>().unwrap()]
+        );
+        watch.abort();
+        let _ = std::fs::remove_file(path);
+    }
+}
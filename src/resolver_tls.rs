@@ -0,0 +1,138 @@
+use hickory_proto::rustls::{client_config, default_provider};
+use ring::digest::{SHA256, digest};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, Error, SignatureScheme};
+use rustls_platform_verifier::Verifier;
+use std::sync::Arc;
+
+/// A TLS client config which also requires one of the certificates sent by
+/// the server to match a pinned hash from a DNS stamp.
+pub fn pinned_client_config(hashes: Vec<[u8; 32]>) -> ClientConfig {
+    let mut config = client_config();
+    let inner = Verifier::new().with_provider(Arc::new(default_provider()));
+    config
+        .dangerous()
+        .set_certificate_verifier(Arc::new(PinnedCertVerifier { inner, hashes }));
+    config
+}
+
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    inner: Verifier,
+    hashes: Vec<[u8; 32]>,
+}
+
+impl PinnedCertVerifier {
+    fn is_pinned(&self, cert: &CertificateDer<'_>) -> bool {
+        tbs_certificate(cert).is_some_and(|tbs| {
+            let hash = digest(&SHA256, tbs);
+            self.hashes.iter().any(|pin| pin == hash.as_ref())
+        })
+    }
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        if std::iter::once(end_entity)
+            .chain(intermediates)
+            .any(|cert| self.is_pinned(cert))
+        {
+            Ok(verified)
+        } else {
+            Err(Error::General(
+                "no certificate matches the pinned hashes".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// The DER encoded `tbsCertificate`, the first element of the certificate sequence.
+fn tbs_certificate(cert: &[u8]) -> Option<&[u8]> {
+    let (_, certificate) = der_sequence(cert)?;
+    let (len, _) = der_sequence(certificate)?;
+    certificate.get(..len)
+}
+
+/// Parse a DER sequence header, returning the length of the whole element and
+/// its contents.
+fn der_sequence(data: &[u8]) -> Option<(usize, &[u8])> {
+    if *data.first()? != 0x30 {
+        return None;
+    }
+    let first = *data.get(1)? as usize;
+    let (header_len, len) = if first < 0x80 {
+        (2, first)
+    } else {
+        let octets = first & 0x7f;
+        if octets == 0 || octets > 4 {
+            return None;
+        }
+        let len = data
+            .get(2..2 + octets)?
+            .iter()
+            .fold(0, |len, b| (len << 8) | *b as usize);
+        (2 + octets, len)
+    };
+    let contents = data.get(header_len..header_len + len)?;
+    Some((header_len + len, contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tbs_certificate_of_der() {
+        // SEQUENCE { SEQUENCE { INTEGER 1 }, SEQUENCE {}, BIT STRING }
+        let cert = [
+            0x30, 0x0b, 0x30, 0x03, 0x02, 0x01, 0x01, 0x30, 0x00, 0x03, 0x02, 0x00, 0xff,
+        ];
+        assert_eq!(
+            tbs_certificate(&cert),
+            Some(&[0x30, 0x03, 0x02, 0x01, 0x01][..])
+        );
+        assert_eq!(tbs_certificate(&cert[..6]), None);
+        assert_eq!(tbs_certificate(&[0x02, 0x01, 0x01]), None);
+
+        let mut long = vec![0x30, 0x82, 0x01, 0x04, 0x30, 0x82, 0x01, 0x00];
+        long.resize(0x108, 0);
+        assert_eq!(tbs_certificate(&long).map(<[u8]>::len), Some(0x104));
+    }
+}
@@ -0,0 +1,86 @@
+use hickory_proto::rr::{Record, RecordType};
+use hickory_proto::xfer::{DnsRequest, DnsResponse};
+use std::sync::{Arc, Mutex};
+
+tokio::task_local! {
+    /// Keeps the sections of the responses to the current lookup
+    static RECORDER: Recorder;
+}
+
+/// The authority and additional sections of a response
+#[derive(Debug, Clone, Default)]
+pub struct Sections {
+    pub name_servers: Vec<Record>,
+    pub additionals: Vec<Record>,
+}
+
+#[derive(Clone)]
+pub struct Recorder {
+    record_type: RecordType,
+    sections: Arc<Mutex<Option<Sections>>>,
+}
+
+impl Recorder {
+    pub fn record(&self, response: &DnsResponse) {
+        *self.sections.lock().unwrap() = Some(Sections {
+            name_servers: response.name_servers().to_vec(),
+            additionals: response.additionals().to_vec(),
+        });
+    }
+}
+
+/// Run `lookup` of `record_type` records, returning the sections of the last
+/// response to a query of that type, none when no such response came.
+pub async fn capture<F: Future>(
+    record_type: RecordType,
+    lookup: F,
+) -> (F::Output, Option<Sections>) {
+    let recorder = Recorder {
+        record_type,
+        sections: Arc::default(),
+    };
+    let output = RECORDER.scope(recorder.clone(), lookup).await;
+    let sections = recorder.sections.lock().unwrap().take();
+    (output, sections)
+}
+
+/// The recorder of the current lookup when `request` is one of its queries,
+/// leaving out the ones validating DNSSEC.
+pub fn recorder(request: &DnsRequest) -> Option<Recorder> {
+    let recorder = RECORDER.try_with(Recorder::clone).ok()?;
+    request
+        .queries()
+        .iter()
+        .any(|query| query.query_type() == recorder.record_type)
+        .then_some(recorder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::op::{Message, Query};
+    use hickory_proto::rr::Name;
+
+    fn request(record_type: RecordType) -> DnsRequest {
+        let mut message = Message::new();
+        message.add_query(Query::query(
+            Name::from_ascii("example.com.").unwrap(),
+            record_type,
+        ));
+        DnsRequest::new(message, Default::default())
+    }
+
+    #[tokio::test]
+    async fn recorder_of_the_lookup_type() {
+        let (recorded, _) = capture(RecordType::A, async {
+            // the DNSSEC validation asks for other types
+            assert!(recorder(&request(RecordType::DNSKEY)).is_none());
+            assert!(recorder(&request(RecordType::AAAA)).is_none());
+            recorder(&request(RecordType::A)).is_some()
+        })
+        .await;
+        assert!(recorded);
+        // outside of a lookup
+        assert!(recorder(&request(RecordType::A)).is_none());
+    }
+}
@@ -0,0 +1,101 @@
+use crate::admin;
+use crate::config::{AdminConfig, Config, DnstapConfig, QueryLogConfig};
+use crate::dnstap::Dnstap;
+use crate::handler::Handler;
+use crate::handler_config::HandlerConfig;
+use crate::metrics;
+use crate::querylog::QueryLog;
+use hickory_server::ServerFuture;
+use log::info;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, UdpSocket};
+
+/// The listeners of a server, and the query log and dnstap it sets up
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub bind: SocketAddr,
+    pub metrics: Option<SocketAddr>,
+    pub admin: Option<AdminConfig>,
+    pub query_log: Option<QueryLogConfig>,
+    pub dnstap: Option<DnstapConfig>,
+}
+
+impl From<&Config> for ServerConfig {
+    fn from(config: &Config) -> Self {
+        ServerConfig {
+            bind: config.bind,
+            metrics: config.metrics,
+            admin: config.admin.clone(),
+            query_log: config.query_log.clone(),
+            dnstap: config.dnstap.clone(),
+        }
+    }
+}
+
+/// Serve `config` until the listeners fail: DNS over UDP and TCP on its bind
+/// address, and the metrics, the admin API, the query log and dnstap it sets up.
+/// The admin API reloads the config from `config_path`, if it was read from a file.
+pub async fn run(config: Config, config_path: Option<PathBuf>) -> io::Result<()> {
+    let server = ServerConfig::from(&config);
+    run_with(config.into(), server, config_path).await
+}
+
+/// Serve `config` like [`run`], with the listeners of `server`. The resolvers added
+/// to `config` in code are kept when the admin API reloads it.
+pub async fn run_with(
+    mut config: HandlerConfig,
+    server: ServerConfig,
+    config_path: Option<PathBuf>,
+) -> io::Result<()> {
+    let ServerConfig {
+        bind: bind_socket,
+        metrics: metrics_socket,
+        admin,
+        query_log,
+        dnstap,
+    } = server;
+    config.query_log = query_log
+        .map(|query_log| {
+            let path = query_log.path.clone();
+            QueryLog::open(query_log, config.metrics.clone()).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("failed to open the query log {}: {}", path.display(), e),
+                )
+            })
+        })
+        .transpose()?
+        .map(Arc::new);
+    config.dnstap = dnstap.map(|dnstap| Arc::new(Dnstap::new(dnstap, config.metrics.clone())));
+    let handler = Handler::new(config);
+    if let Some(metrics_socket) = metrics_socket {
+        let listener = TcpListener::bind(metrics_socket).await?;
+        info!("Serving metrics on HTTP: {}", metrics_socket);
+        tokio::spawn(metrics::serve(listener, handler.shared_config()));
+    }
+    if let Some(admin) = admin {
+        let listener = TcpListener::bind(admin.listen).await?;
+        info!("Serving the admin API on HTTP: {}", admin.listen);
+        tokio::spawn(admin::serve(
+            listener,
+            admin.token,
+            handler.shared_config(),
+            config_path,
+        ));
+    }
+    let mut server = ServerFuture::new(handler);
+
+    let bind = UdpSocket::bind(bind_socket).await?;
+    info!("Listening on UDP: {}", bind_socket);
+    server.register_socket(bind);
+
+    let bind = TcpListener::bind(bind_socket).await?;
+    info!("Listening on TCP: {}", bind_socket);
+    server.register_listener(bind, Duration::from_secs(10));
+
+    Ok(server.block_until_done().await?)
+}
@@ -0,0 +1,389 @@
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hickory_proto::rr::Name;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use thiserror::Error;
+
+const PROTOCOL_PLAIN: u8 = 0x00;
+const PROTOCOL_DNSCRYPT: u8 = 0x01;
+const PROTOCOL_DOH: u8 = 0x02;
+const PROTOCOL_DOT: u8 = 0x03;
+const PROTOCOL_DOQ: u8 = 0x04;
+
+/// A DNS stamp as described in https://dnscrypt.info/stamps-specifications
+#[derive(Debug, Clone, PartialEq)]
+pub enum DnsStamp {
+    Plain {
+        props: u64,
+        address: SocketAddr,
+    },
+    DnsCrypt {
+        props: u64,
+        address: SocketAddr,
+        public_key: [u8; 32],
+        provider_name: String,
+    },
+    DoH {
+        props: u64,
+        server: TlsServer,
+        path: String,
+    },
+    DoT {
+        props: u64,
+        server: TlsServer,
+    },
+    DoQ {
+        props: u64,
+        server: TlsServer,
+    },
+}
+
+/// The server of a DoH, DoT or DoQ stamp
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsServer {
+    /// Missing when the hostname has to be resolved
+    pub address: Option<IpAddr>,
+    pub port: u16,
+    pub hostname: String,
+    /// SHA256 digests of the TBS certificates, one of which must be in the chain
+    pub hashes: Vec<[u8; 32]>,
+}
+
+#[derive(Error, Debug)]
+pub enum StampError {
+    #[error("stamp must start with sdns://")]
+    InvalidScheme,
+    #[error("{0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error("stamp is truncated")]
+    Truncated,
+    #[error("unsupported stamp protocol {0:#04x}")]
+    UnsupportedProtocol(u8),
+    #[error("invalid address {0:?} in stamp")]
+    InvalidAddress(String),
+    #[error("invalid public key in stamp")]
+    InvalidPublicKey,
+    #[error("invalid provider name in stamp")]
+    InvalidProviderName,
+    #[error("invalid hostname in stamp")]
+    InvalidHostname,
+    #[error("invalid certificate hash in stamp")]
+    InvalidHash,
+    #[error("invalid path in stamp")]
+    InvalidPath,
+}
+
+impl DnsStamp {
+    pub fn protocol(&self) -> &'static str {
+        match self {
+            DnsStamp::Plain { .. } => "plain DNS",
+            DnsStamp::DnsCrypt { .. } => "DNSCrypt",
+            DnsStamp::DoH { .. } => "DNS-over-HTTPS",
+            DnsStamp::DoT { .. } => "DNS-over-TLS",
+            DnsStamp::DoQ { .. } => "DNS-over-QUIC",
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            DnsStamp::Plain { address, .. } | DnsStamp::DnsCrypt { address, .. } => address.port(),
+            DnsStamp::DoH { server, .. }
+            | DnsStamp::DoT { server, .. }
+            | DnsStamp::DoQ { server, .. } => server.port,
+        }
+    }
+
+    pub fn address(&self) -> Option<SocketAddr> {
+        match self {
+            DnsStamp::Plain { address, .. } | DnsStamp::DnsCrypt { address, .. } => Some(*address),
+            DnsStamp::DoH { server, .. }
+            | DnsStamp::DoT { server, .. }
+            | DnsStamp::DoQ { server, .. } => server
+                .address
+                .map(|address| SocketAddr::new(address, server.port)),
+        }
+    }
+
+    #[cfg(any(
+        feature = "dns-over-tls",
+        feature = "dns-over-https",
+        feature = "dns-over-h3",
+        feature = "dns-over-quic"
+    ))]
+    pub fn tls_server(&self) -> Option<&TlsServer> {
+        match self {
+            DnsStamp::DoH { server, .. }
+            | DnsStamp::DoT { server, .. }
+            | DnsStamp::DoQ { server, .. } => Some(server),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for DnsStamp {
+    type Err = StampError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let encoded = s.strip_prefix("sdns://").ok_or(StampError::InvalidScheme)?;
+        let bytes = URL_SAFE_NO_PAD.decode(encoded.trim_end_matches('='))?;
+        let mut reader = StampReader(&bytes);
+        match reader.u8()? {
+            PROTOCOL_PLAIN => {
+                let props = reader.props()?;
+                let address = parse_address(&reader.string()?, 53)?;
+                Ok(DnsStamp::Plain { props, address })
+            }
+            PROTOCOL_DNSCRYPT => {
+                let props = reader.props()?;
+                let address = parse_address(&reader.string()?, 443)?;
+                let public_key: [u8; 32] = reader
+                    .lp()?
+                    .try_into()
+                    .map_err(|_| StampError::InvalidPublicKey)?;
+                #[cfg(feature = "dnscrypt")]
+                ed25519_dalek::VerifyingKey::from_bytes(&public_key)
+                    .map_err(|_| StampError::InvalidPublicKey)?;
+                let provider_name = reader.string()?;
+                if provider_name.is_empty() || Name::from_str(&provider_name).is_err() {
+                    return Err(StampError::InvalidProviderName);
+                }
+                Ok(DnsStamp::DnsCrypt {
+                    props,
+                    address,
+                    public_key,
+                    provider_name,
+                })
+            }
+            PROTOCOL_DOH => {
+                let props = reader.props()?;
+                let server = reader.tls_server(443)?;
+                let path = reader.string()?;
+                if !path.starts_with('/') {
+                    return Err(StampError::InvalidPath);
+                }
+                Ok(DnsStamp::DoH {
+                    props,
+                    server,
+                    path,
+                })
+            }
+            PROTOCOL_DOT => {
+                let props = reader.props()?;
+                let server = reader.tls_server(853)?;
+                Ok(DnsStamp::DoT { props, server })
+            }
+            PROTOCOL_DOQ => {
+                let props = reader.props()?;
+                let server = reader.tls_server(853)?;
+                Ok(DnsStamp::DoQ { props, server })
+            }
+            protocol => Err(StampError::UnsupportedProtocol(protocol)),
+        }
+    }
+}
+
+/// Split the optional port from `hostname:port`.
+fn parse_hostname(hostname: &str) -> Result<(String, Option<u16>), StampError> {
+    let (hostname, port) = match hostname.rsplit_once(':') {
+        Some((hostname, port)) => (
+            hostname,
+            Some(port.parse().map_err(|_| StampError::InvalidHostname)?),
+        ),
+        None => (hostname, None),
+    };
+    if hostname.is_empty() || Name::from_str(hostname).is_err() {
+        return Err(StampError::InvalidHostname);
+    }
+    Ok((hostname.to_string(), port))
+}
+
+/// Parse `ip`, `ip:port` or `[ipv6]:port`.
+fn parse_address(addr: &str, default_port: u16) -> Result<SocketAddr, StampError> {
+    addr.parse::<SocketAddr>()
+        .or_else(|_| {
+            addr.trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<IpAddr>()
+                .map(|ip| SocketAddr::new(ip, default_port))
+        })
+        .map_err(|_| StampError::InvalidAddress(addr.to_string()))
+}
+
+struct StampReader<'a>(&'a [u8]);
+
+impl<'a> StampReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StampError> {
+        if self.0.len() < len {
+            return Err(StampError::Truncated);
+        }
+        let (data, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(data)
+    }
+
+    fn u8(&mut self) -> Result<u8, StampError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn props(&mut self) -> Result<u64, StampError> {
+        let props = self.take(8)?;
+        Ok(u64::from_le_bytes(props.try_into().unwrap()))
+    }
+
+    /// A length-prefixed byte string
+    fn lp(&mut self) -> Result<&'a [u8], StampError> {
+        let len = self.u8()?;
+        self.take(len as usize)
+    }
+
+    /// A variable length list of byte strings, the high bit of each length
+    /// marks that more items follow
+    fn vlp(&mut self) -> Result<Vec<&'a [u8]>, StampError> {
+        let mut items = Vec::new();
+        loop {
+            let len = self.u8()?;
+            items.push(self.take((len & 0x7f) as usize)?);
+            if len & 0x80 == 0 {
+                return Ok(items);
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, StampError> {
+        let data = self.lp()?;
+        String::from_utf8(data.to_vec()).map_err(|_| StampError::InvalidProviderName)
+    }
+
+    /// `addr || hashes || hostname`, the port defaults to the one in the
+    /// hostname and then to `default_port`
+    fn tls_server(&mut self, default_port: u16) -> Result<TlsServer, StampError> {
+        let addr = self.string()?;
+        let hashes = self
+            .vlp()?
+            .into_iter()
+            .filter(|hash| !hash.is_empty())
+            .map(|hash| hash.try_into().map_err(|_| StampError::InvalidHash))
+            .collect::<Result<Vec<[u8; 32]>, _>>()?;
+        let (hostname, hostname_port) =
+            parse_hostname(&self.string().map_err(|_| StampError::InvalidHostname)?)?;
+        let default_port = hostname_port.unwrap_or(default_port);
+        let (address, port) = match addr.strip_prefix(':') {
+            _ if addr.is_empty() => (None, default_port),
+            Some(port) => (
+                None,
+                port.parse()
+                    .map_err(|_| StampError::InvalidAddress(addr.clone()))?,
+            ),
+            None => {
+                let address = parse_address(&addr, default_port)?;
+                (Some(address.ip()), address.port())
+            }
+        };
+        Ok(TlsServer {
+            address,
+            port,
+            hostname,
+            hashes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dnscrypt_stamp() {
+        let stamp: DnsStamp = "sdns://AQMAAAAAAAAAEzE0OS4xMTIuMTEyLjEwOjg0NDMgZ8hHuMh1jNEgJFVDvnVnRt803x2EwAuMRwNo34Idhj4ZMi5kbnNjcnlwdC1jZXJ0LnF1YWQ5Lm5ldA"
+            .parse()
+            .unwrap();
+        let DnsStamp::DnsCrypt {
+            props,
+            address,
+            public_key,
+            provider_name,
+        } = stamp
+        else {
+            panic!("unexpected stamp {stamp:?}");
+        };
+        assert_eq!(props, 3);
+        assert_eq!(address, "149.112.112.10:8443".parse().unwrap());
+        assert_eq!(public_key[..4], [0x67, 0xc8, 0x47, 0xb8]);
+        assert_eq!(provider_name, "2.dnscrypt-cert.quad9.net");
+    }
+
+    #[test]
+    fn plain_stamp() {
+        let stamp: DnsStamp = "sdns://AAEAAAAAAAAABzguOC44Ljg".parse().unwrap();
+        assert_eq!(
+            stamp,
+            DnsStamp::Plain {
+                props: 1,
+                address: "8.8.8.8:53".parse().unwrap(),
+            }
+        );
+    }
+
+    #[test]
+    fn tls_stamps() {
+        let hash: [u8; 32] = std::array::from_fn(|i| i as u8);
+        let stamp: DnsStamp = "sdns://AgEAAAAAAAAABzkuOS45LjmgAAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8ADWRucy5xdWFkOS5uZXQKL2Rucy1xdWVyeQ"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            stamp,
+            DnsStamp::DoH {
+                props: 1,
+                server: TlsServer {
+                    address: Some("9.9.9.9".parse().unwrap()),
+                    port: 443,
+                    hostname: "dns.quad9.net".to_string(),
+                    hashes: vec![hash],
+                },
+                path: "/dns-query".to_string(),
+            }
+        );
+
+        let stamp: DnsStamp = "sdns://AwEAAAAAAAAAAAAUZG5zLmV4YW1wbGUuY29tOjg4NTM"
+            .parse()
+            .unwrap();
+        assert_eq!(stamp.address(), None);
+        assert_eq!(stamp.port(), 8853);
+        assert!(matches!(
+            stamp,
+            DnsStamp::DoT { server, .. } if server.hostname == "dns.example.com"
+        ));
+
+        let stamp: DnsStamp =
+            "sdns://BAEAAAAAAAAABDo3ODQgAAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8PZG5zLmV4YW1wbGUuY29t"
+                .parse()
+                .unwrap();
+        assert_eq!(stamp.port(), 784);
+        assert!(matches!(
+            stamp,
+            DnsStamp::DoQ { server, .. } if server.hashes == vec![hash]
+        ));
+
+        assert!(matches!(
+            "sdns://AgEAAAAAAAAAAARhYmNkD2Rucy5leGFtcGxlLmNvbQEv".parse::<DnsStamp>(),
+            Err(StampError::InvalidHash)
+        ));
+    }
+
+    #[test]
+    fn invalid_stamps() {
+        assert!(matches!(
+            "https://example.com".parse::<DnsStamp>(),
+            Err(StampError::InvalidScheme)
+        ));
+        assert!(matches!(
+            "sdns://AQMAAAAAAAAAEzE0OS4xMTIu".parse::<DnsStamp>(),
+            Err(StampError::Truncated)
+        ));
+        assert!(matches!(
+            "sdns://BwAAAAAAAAAA".parse::<DnsStamp>(),
+            Err(StampError::UnsupportedProtocol(0x07))
+        ));
+    }
+}